@@ -0,0 +1,133 @@
+//! compare `tokio::io::copy` over a russh-sftp `File` with the pipelined transfer engine.
+//!
+//! the sftp server is a local `sftp-server` subprocess talking over stdin/stdout,
+//! set `SFTP_SERVER` to its path if it is not in one of the usual places.
+//! `BENCH_SIZE_MB` sets the size of the test file (default 64), and
+//! `BENCH_LATENCY_MS` delays every server reply to mimic a slow transport (default 0).
+#[path = "../src/transfer.rs"]
+mod transfer;
+
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use russh_sftp::client::SftpSession;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::process::{Child, ChildStdin, Command};
+use transfer::TransferOptions;
+
+const SFTP_SERVERS: [&str; 4] = [
+    "/usr/lib/openssh/sftp-server",
+    "/usr/libexec/openssh/sftp-server",
+    "/usr/lib/ssh/sftp-server",
+    "/usr/libexec/sftp-server",
+];
+
+struct ChildStream{
+    stdin: ChildStdin,
+    stdout: DuplexStream,
+}
+
+impl AsyncRead for ChildStream{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChildStream{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>>{
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+async fn start_server(path: &str,latency: Duration) -> Result<(Child,SftpSession),Box<dyn std::error::Error>>{
+    let mut child = Command::new(path).stdin(Stdio::piped()).stdout(Stdio::piped()).kill_on_drop(true).spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    // replies reach the client `latency` after the server sent them, without limiting how many are on the way
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move{
+        let mut buf = vec![0; 65536];
+        while let Ok(n) = stdout.read(&mut buf).await{
+            if n == 0 || tx.send((Instant::now() + latency, buf[..n].to_vec())).is_err(){
+                break;
+            }
+        }
+    });
+    let (client, mut server) = tokio::io::duplex(1 << 20);
+    tokio::spawn(async move{
+        while let Some((deadline, data)) = rx.recv().await{
+            tokio::time::sleep_until(deadline.into()).await;
+            if server.write_all(&data).await.is_err(){
+                break;
+            }
+        }
+    });
+    let stream = ChildStream{ stdin: child.stdin.take().unwrap(), stdout: client };
+    // the settings the client runs its transfers with
+    let options = TransferOptions{ chunk_size: transfer::CHUNK_SIZE, concurrency: transfer::CONCURRENCY, verify: false, atomic: false, limit_rate: None };
+    let sftp = SftpSession::new_with_config(stream, transfer::session_config(&options)?).await?;
+    Ok((child,sftp))
+}
+
+fn report(name: &str,bytes: u64,start: Instant){
+    let secs = start.elapsed().as_secs_f64();
+    println!("{:<40} {:>8.1} MB/s ({:.3}s)",name,bytes as f64 / secs / 1e6,secs);
+}
+
+#[tokio::main]
+async fn main() -> Result<(),Box<dyn std::error::Error>>{
+    let server = match std::env::var("SFTP_SERVER"){
+        Ok(path) => path,
+        Err(_) => match SFTP_SERVERS.iter().find(|p| std::path::Path::new(p).exists()){
+            Some(path) => path.to_string(),
+            None =>{
+                println!("sftp-server not found, set SFTP_SERVER to run this benchmark");
+                return Ok(());
+            }
+        },
+    };
+    let size_mb: usize = std::env::var("BENCH_SIZE_MB").ok().and_then(|s| s.parse().ok()).unwrap_or(64);
+    let latency = Duration::from_millis(std::env::var("BENCH_LATENCY_MS").ok().and_then(|s| s.parse().ok()).unwrap_or(0));
+    let dir = std::env::temp_dir().join(format!("sftp-bench-{}",std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let source = dir.join("source");
+    let data: Vec<u8> = (0..size_mb * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &data)?;
+    let remote = dir.join("remote").to_string_lossy().into_owned();
+    let (_child,sftp) = start_server(&server, latency).await?;
+
+    let start = Instant::now();
+    let mut local = tokio::fs::File::open(&source).await?;
+    let mut remote_file = sftp.create(remote.as_str()).await?;
+    let bytes = tokio::io::copy(&mut local, &mut remote_file).await?;
+    drop(remote_file);
+    report("upload tokio::io::copy", bytes, start);
+
+    let start = Instant::now();
+    let mut remote_file = sftp.open(remote.as_str()).await?;
+    let bytes = tokio::io::copy(&mut remote_file, &mut tokio::io::sink()).await?;
+    drop(remote_file);
+    report("download tokio::io::copy", bytes, start);
+
+    for (chunk_size, concurrency) in [(32768, 1), (32768, 4), (32768, 16), (32768, 64), (261120, 16)]{
+        let options = TransferOptions{ chunk_size, concurrency, verify: false, atomic: false, limit_rate: None };
+        let start = Instant::now();
+        let mut local = tokio::fs::File::open(&source).await?;
+        let bytes = transfer::upload(&sftp, &mut local, &remote, Some(data.len() as u64), &options).await?;
+        report(&format!("upload chunk={} concurrency={}",chunk_size,concurrency), bytes, start);
+
+        let start = Instant::now();
+        let mut downloaded = vec![];
+        let bytes = transfer::download(&sftp, &remote, &mut downloaded, &options).await?;
+        report(&format!("download chunk={} concurrency={}",chunk_size,concurrency), bytes, start);
+        assert!(downloaded == data, "downloaded data differs");
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -16,6 +16,8 @@ pub struct AsyncFsStream{
     file: Option<File>
 }
 impl AsyncFsStream{
+    // AsyncFd::register replaces AsyncFd::new only in newer tokio
+    #[allow(deprecated)]
     pub fn new(fd: RawFd,fd_is_file: bool) -> Result<Self>{
         unsafe{
             libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK);
@@ -0,0 +1,185 @@
+use crossterm::terminal::{enable_raw_mode, disable_raw_mode, window_size};
+use futures::StreamExt;
+use signal_hook::consts::signal::SIGWINCH;
+use signal_hook_tokio::Signals;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::info;
+
+use crate::async_fs_stream::AsyncFsStream;
+use crate::auth::AuthOptions;
+use crate::endpoint::Endpoint;
+use crate::extract_websocket_stream::ExtractWebsocketStream;
+
+// the attach stream carries frames: a tag, the payload length as a big endian u32, then the payload
+const STDIN: u8 = 0;
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+/// the command finished, the payload is its exit code as a big endian i32
+const EXIT: u8 = 3;
+/// the terminal size, columns and rows as big endian u16
+const RESIZE: u8 = 4;
+const STDIN_EOF: u8 = 5;
+/// ctrl-] leaves the command running and returns
+const DETACH: u8 = 0x1d;
+/// larger frames are refused instead of allocated
+const MAX_FRAME: usize = 16 << 20;
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W,tag: u8,payload: &[u8]) -> std::io::Result<()>{
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    frame.push(tag);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// the next frame, `None` once the stream ends. a frame over `MAX_FRAME` is an error that ends it too
+async fn read_frame<R: AsyncRead + Unpin>(reader: Option<R>) -> Option<(std::io::Result<(u8, Vec<u8>)>, Option<R>)>{
+    let mut reader = reader?;
+    let mut header = [0u8; 5];
+    if let Err(e) = reader.read_exact(&mut header).await{
+        info!("attach read:{:?}",e);
+        return None;
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME{
+        let e = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("attach frame of {} bytes, the limit is {}",len,MAX_FRAME));
+        return Some((Err(e), None));
+    }
+    let mut payload = vec![0u8; len];
+    if let Err(e) = reader.read_exact(&mut payload).await{
+        info!("attach read:{:?}",e);
+        return None;
+    }
+    Some((Ok((header[0], payload)), Some(reader)))
+}
+
+/// the terminal in raw mode until dropped, so it is restored on errors too
+struct RawMode;
+
+impl RawMode{
+    fn enable() -> std::io::Result<Self>{
+        enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode{
+    fn drop(&mut self){
+        let _ = disable_raw_mode();
+    }
+}
+
+async fn resize<W: AsyncWrite + Unpin>(writer: &mut W) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let size = window_size()?;
+    let mut payload = size.columns.to_be_bytes().to_vec();
+    payload.extend_from_slice(&size.rows.to_be_bytes());
+    Ok(write_frame(writer, RESIZE, &payload).await?)
+}
+
+/// connect to a running command over the agent's websocket and relay stdin, stdout and stderr
+/// until it exits, returns its exit code, or `None` when detached
+pub async fn attach_loop(
+    endpoint: &Endpoint,
+    command_id: &str,
+    auth: &AuthOptions) -> std::result::Result<Option<i32>,Box<dyn std::error::Error>>{
+    let path = format!("/ops/attach_command?{}",serde_urlencoded::to_string([("command_id", command_id)])?);
+    let stream = endpoint.connect(auth.connector(endpoint)?.as_ref()).await?;
+    relay(ExtractWebsocketStream::handshake(endpoint.ws_url(&path), stream, auth).await?).await
+}
+
+type Writer = Box<dyn AsyncWrite + Unpin>;
+
+async fn relay<S: AsyncRead + AsyncWrite + Unpin>(stream: S) -> std::result::Result<Option<i32>,Box<dyn std::error::Error>>{
+    let tty = unsafe { libc::isatty(libc::STDIN_FILENO) == 1 };
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut frames = Box::pin(futures::stream::unfold(Some(reader), read_frame));
+    let _raw_mode = if tty{ Some(RawMode::enable()?) }else{ None };
+    // like ssh_loop, a raw terminal is read and written through the same non blocking fd,
+    // stderr goes there too
+    let (mut input, mut output, mut errors): (Box<dyn AsyncRead + Unpin>, Writer, Option<Writer>) = if tty{
+        let (input, output) = tokio::io::split(AsyncFsStream::new(libc::STDIN_FILENO, false)?);
+        (Box::new(input), Box::new(output), None)
+    }else{
+        (Box::new(tokio::io::stdin()), Box::new(tokio::io::stdout()), Some(Box::new(tokio::io::stderr())))
+    };
+    let mut signals = Signals::new([SIGWINCH])?;
+    let handle = signals.handle();
+    let result = async{
+        if tty{
+            resize(&mut writer).await?;
+        }
+        let mut stdin_open = true;
+        let mut buffer = vec![0u8; 4096];
+        loop{
+            tokio::select! {
+                Some(_) = signals.next(), if tty =>{
+                    resize(&mut writer).await?;
+                }
+                n = input.read(&mut buffer), if stdin_open =>{
+                    let data = &buffer[..n?];
+                    if data.is_empty(){
+                        write_frame(&mut writer, STDIN_EOF, &[]).await?;
+                        stdin_open = false;
+                    }else if let Some(i) = data.iter().position(|b| tty && *b == DETACH){
+                        write_frame(&mut writer, STDIN, &data[..i]).await?;
+                        return Ok(None);
+                    }else{
+                        write_frame(&mut writer, STDIN, data).await?;
+                    }
+                }
+                frame = frames.next() =>{
+                    match frame{
+                        Some(Ok((STDOUT, data))) =>{
+                            output.write_all(&data).await?;
+                            output.flush().await?;
+                        }
+                        Some(Ok((STDERR, data))) =>{
+                            let errors = errors.as_mut().unwrap_or(&mut output);
+                            errors.write_all(&data).await?;
+                            errors.flush().await?;
+                        }
+                        Some(Ok((EXIT, data))) =>{
+                            let code = data.try_into().map(i32::from_be_bytes).map_err(|_| "bad exit frame")?;
+                            return Ok(Some(code));
+                        }
+                        Some(Ok((tag, _))) => info!("unknown attach frame {}",tag),
+                        Some(Err(e)) => return Err(e.into()),
+                        None => return Err::<_,Box<dyn std::error::Error>>("attach stream closed".into()),
+                    }
+                }
+            }
+        }
+    }.await;
+    handle.close();
+    result
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_roundtrip(){
+        let mut data = vec![];
+        write_frame(&mut data, STDOUT, b"hello").await.unwrap();
+        let (frame, rest) = read_frame(Some(data.as_slice())).await.unwrap();
+        assert_eq!(frame.unwrap(), (STDOUT, b"hello".to_vec()));
+        assert!(read_frame(rest).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_ends_the_stream(){
+        let mut data = vec![STDOUT];
+        data.extend_from_slice(&(MAX_FRAME as u32 + 1).to_be_bytes());
+        let (frame, rest) = read_frame(Some(data.as_slice())).await.unwrap();
+        assert_eq!(frame.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(rest.is_none());
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_ends_the_stream(){
+        let data = [STDOUT, 0, 0, 0, 9, b'x'];
+        assert!(read_frame(Some(&data[..])).await.is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use clap::Args;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::endpoint::Endpoint;
+
+/// credentials and tls settings for the agent, used by exec and the ssh websocket alike,
+/// flags and environment win over the config file
+#[derive(Args, Debug, Clone, Default)]
+pub struct AuthOptions{
+    /// bearer token sent to the agent
+    #[arg(long, env = "RUSSH_OPS_TOKEN", hide_env_values = true, global = true)]
+    pub token: Option<String>,
+    /// file holding the bearer token, used when --token is not given
+    #[arg(long, env = "RUSSH_OPS_TOKEN_FILE", global = true)]
+    pub token_file: Option<PathBuf>,
+    /// extra header sent to the agent, `Name: value`, repeatable
+    #[arg(short = 'H', long = "header", value_name = "HEADER", global = true, value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+    /// websocket subprotocol offered to the agent, repeatable or comma separated
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub subprotocol: Vec<String>,
+    /// sign requests with hmac-sha256 using this key
+    #[arg(long, env = "RUSSH_OPS_HMAC_KEY", hide_env_values = true, global = true)]
+    pub hmac_key: Option<String>,
+    /// talk to the agent over https and wss, implied by the certificate options
+    #[arg(long, global = true)]
+    pub tls: bool,
+    /// pem bundle of the authorities trusted to sign the agent's certificate, besides the system ones
+    #[arg(long, env = "RUSSH_OPS_CA_CERT", global = true)]
+    pub ca_cert: Option<PathBuf>,
+    /// pem client certificate for mutual tls
+    #[arg(long, env = "RUSSH_OPS_CLIENT_CERT", global = true)]
+    pub client_cert: Option<PathBuf>,
+    /// pem pkcs8 key of the client certificate
+    #[arg(long, env = "RUSSH_OPS_CLIENT_KEY", global = true)]
+    pub client_key: Option<PathBuf>,
+    /// name sent in sni and checked against the agent's certificate, instead of the host
+    #[arg(long, global = true)]
+    pub tls_server_name: Option<String>,
+    /// accept any certificate from the agent, for testing only
+    #[arg(long, global = true)]
+    pub insecure: bool,
+    /// tls was asked for by the config file alone, not on the command line
+    #[arg(skip)]
+    tls_from_config: bool,
+}
+
+/// `$HOME/russh-ssh-client.json`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Config{
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    headers: BTreeMap<String, String>,
+    subprotocol: Vec<String>,
+    hmac_key: Option<String>,
+    tls: bool,
+    ca_cert: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    tls_server_name: Option<String>,
+}
+
+/// hex hmac-sha256 of "<method>\n<path>\n<timestamp>\n" followed by the body
+fn sign(key: &str,method: &str,path: &str,timestamp: &str,body: &[u8]) -> String{
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac takes any key length");
+    mac.update(format!("{}\n{}\n{}\n",method,path,timestamp).as_bytes());
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}",b)).collect()
+}
+
+/// `Name: value`
+fn parse_header(header: &str) -> std::result::Result<(String, String),String>{
+    let (name, value) = header.split_once(':').ok_or("expected `Name: value`")?;
+    let (name, value) = (name.trim(), value.trim());
+    http::HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("header name \"{}\": {}",name,e))?;
+    http::HeaderValue::from_str(value).map_err(|e| format!("header value \"{}\": {}",value,e))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn read(path: &Path) -> std::result::Result<Vec<u8>,Box<dyn std::error::Error>>{
+    std::fs::read(path).map_err(|e| format!("{}: {}",path.display(),e).into())
+}
+
+impl AuthOptions{
+    /// fill what was not given on the command line from the config file
+    pub fn with_config(mut self) -> std::result::Result<Self,Box<dyn std::error::Error>>{
+        let path = std::env::var("HOME")? + "/russh-ssh-client.json";
+        let config: Config = match std::fs::read(&path){
+            Ok(text) => serde_json::from_slice(&text).map_err(|e| format!("{}: {}",path,e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("{}: {}",path,e).into()),
+        };
+        self.token = self.token.or(config.token);
+        self.token_file = self.token_file.or(config.token_file);
+        if let (None, Some(token_file)) = (&self.token, &self.token_file){
+            let token = String::from_utf8(read(token_file)?)?.trim().to_string();
+            if token.is_empty(){
+                return Err(format!("{}: empty token",token_file.display()).into());
+            }
+            self.token = Some(token);
+        }
+        for (name, value) in config.headers{
+            if !self.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(&name)){
+                self.headers.push(parse_header(&format!("{}: {}",name,value))?);
+            }
+        }
+        if self.subprotocol.is_empty(){
+            self.subprotocol = config.subprotocol;
+        }
+        self.hmac_key = self.hmac_key.or(config.hmac_key);
+        self.tls_from_config = !self.uses_tls();
+        self.tls |= config.tls;
+        self.ca_cert = self.ca_cert.or(config.ca_cert);
+        self.client_cert = self.client_cert.or(config.client_cert);
+        self.client_key = self.client_key.or(config.client_key);
+        self.tls_server_name = self.tls_server_name.or(config.tls_server_name);
+        if self.client_cert.is_some() != self.client_key.is_some(){
+            return Err("client_cert and client_key go together".into());
+        }
+        Ok(self)
+    }
+
+    pub fn uses_tls(&self) -> bool{
+        self.tls || self.ca_cert.is_some() || self.client_cert.is_some() || self.tls_server_name.is_some() || self.insecure
+    }
+
+    /// tls on the endpoint when asked for, checked against the server name if one was given.
+    /// only the agent's websocket speaks tls, the flags are an error with plain ssh
+    /// and the config file's settings are left out
+    pub fn apply(&self,endpoint: &mut Endpoint) -> std::result::Result<(),Box<dyn std::error::Error>>{
+        if !endpoint.transport.websocket(){
+            if self.uses_tls() && !self.tls_from_config{
+                return Err("--tls and the certificate options only apply to the agent's websocket, not to plain ssh over tcp or stdio".into());
+            }
+            return Ok(());
+        }
+        endpoint.tls |= self.uses_tls();
+        endpoint.server_name = self.tls_server_name.clone();
+        Ok(())
+    }
+
+    /// the tls connector of an endpoint that uses tls, the certificate files are only read then
+    pub fn connector(&self,endpoint: &Endpoint) -> std::result::Result<Option<native_tls::TlsConnector>,Box<dyn std::error::Error>>{
+        if !endpoint.tls{
+            return Ok(None);
+        }
+        Ok(Some(self.tls_connector()?))
+    }
+
+    /// the extra headers, then `Authorization: Bearer <token>`, and with a hmac key `X-Ops-Timestamp`
+    /// (unix seconds) and `X-Ops-Signature`, the hex hmac-sha256 of "<method>\n<path>\n<timestamp>\n"
+    /// and the body, replacing extra headers of the same name
+    pub fn headers(&self,method: &str,path: &str,body: &[u8]) -> Vec<(String, String)>{
+        let mut headers = vec![];
+        if let Some(token) = &self.token{
+            headers.push(("authorization", format!("Bearer {}",token)));
+        }
+        if let Some(key) = &self.hmac_key{
+            let timestamp = chrono::Utc::now().timestamp().to_string();
+            let signature = sign(key, method, path, &timestamp, body);
+            headers.push(("x-ops-timestamp", timestamp));
+            headers.push(("x-ops-signature", signature));
+        }
+        let mut all: Vec<(String, String)> = self.headers.iter()
+            .filter(|(name, _)| !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+            .cloned()
+            .collect();
+        all.extend(headers.into_iter().map(|(name, value)| (name.to_string(), value)));
+        all
+    }
+
+    /// trusted roots and client identity for https and wss
+    fn tls_connector(&self) -> std::result::Result<native_tls::TlsConnector,Box<dyn std::error::Error>>{
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca_cert) = &self.ca_cert{
+            let certs = native_tls::Certificate::stack_from_pem(&read(ca_cert)?)?;
+            if certs.is_empty(){
+                return Err(format!("{}: no certificates",ca_cert.display()).into());
+            }
+            for cert in certs{
+                builder.add_root_certificate(cert);
+            }
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key){
+            builder.identity(native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)?);
+        }
+        if self.insecure{
+            builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::endpoint::Transport;
+
+    #[test]
+    fn sign_known_values(){
+        assert_eq!(sign("secret", "POST", "/ops/run_command", "1700000000", br#"{"a":1}"#), "cf1b036b0c4044db66011cf266fa17b25685d4e2b1b9912cc904519344bfa69d");
+        assert_eq!(sign("secret", "GET", "/ops/ssh", "1700000000", b""), "cbcca669bddd035270458abae9f05a9985cce9588fdd6781d63e76bdd70129a3");
+    }
+
+    #[test]
+    fn parse_header_trims_name_and_value(){
+        assert_eq!(parse_header("X-Trace: abc").unwrap(), ("X-Trace".into(), "abc".into()));
+        assert_eq!(parse_header("  Host :agent:7777  ").unwrap(), ("Host".into(), "agent:7777".into()));
+        assert_eq!(parse_header("X-Empty:").unwrap(), ("X-Empty".into(), "".into()));
+    }
+
+    #[test]
+    fn parse_header_rejects(){
+        assert!(parse_header("X-Trace abc").is_err());
+        assert!(parse_header(": abc").is_err());
+        assert!(parse_header("X Trace: abc").is_err());
+        assert!(parse_header("X-Trace: a\u{7f}b").is_err());
+    }
+
+    #[test]
+    fn headers_with_token_and_hmac(){
+        let auth = AuthOptions{ token: Some("t0k".into()), hmac_key: Some("secret".into()), ..AuthOptions::default() };
+        let headers = auth.headers("POST", "/ops/stop_command", b"{}");
+        let names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["authorization", "x-ops-timestamp", "x-ops-signature"]);
+        assert_eq!(headers[0].1, "Bearer t0k");
+        assert_eq!(headers[2].1, sign("secret", "POST", "/ops/stop_command", &headers[1].1, b"{}"));
+    }
+
+    #[test]
+    fn headers_without_credentials(){
+        assert!(AuthOptions::default().headers("GET", "/ops/ssh", b"").is_empty());
+    }
+
+    fn endpoint(transport: Transport) -> Endpoint{
+        Endpoint::parse(transport, "host:1").unwrap()
+    }
+
+    #[test]
+    fn apply_tls_to_the_websocket(){
+        let auth = AuthOptions{ tls: true, tls_server_name: Some("agent".into()), ..AuthOptions::default() };
+        let mut ws = endpoint(Transport::Ws);
+        auth.apply(&mut ws).unwrap();
+        assert!(ws.tls);
+        assert_eq!(ws.server_name.as_deref(), Some("agent"));
+    }
+
+    #[test]
+    fn apply_rejects_tls_flags_with_plain_ssh(){
+        let auth = AuthOptions{ tls: true, ..AuthOptions::default() };
+        assert!(auth.apply(&mut endpoint(Transport::Tcp)).is_err());
+        assert!(auth.apply(&mut Endpoint::parse(Transport::Stdio, "nc host 22").unwrap()).is_err());
+        let auth = AuthOptions{ insecure: true, ..AuthOptions::default() };
+        assert!(auth.apply(&mut endpoint(Transport::Tcp)).is_err());
+    }
+
+    #[test]
+    fn apply_leaves_config_tls_out_of_plain_ssh(){
+        let auth = AuthOptions{ tls: true, tls_from_config: true, ..AuthOptions::default() };
+        let mut tcp = endpoint(Transport::Tcp);
+        auth.apply(&mut tcp).unwrap();
+        assert!(!tcp.tls);
+    }
+
+    #[test]
+    fn connector_only_for_tls(){
+        let auth = AuthOptions{ ca_cert: Some("/nonexistent/ca.pem".into()), tls_from_config: true, ..AuthOptions::default() };
+        let mut tcp = endpoint(Transport::Tcp);
+        auth.apply(&mut tcp).unwrap();
+        assert!(auth.connector(&tcp).unwrap().is_none());
+        let mut ws = endpoint(Transport::Ws);
+        auth.apply(&mut ws).unwrap();
+        assert!(auth.connector(&ws).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use russh::{Channel, ChannelMsg, client::Msg};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use log::info;
+
+const BUFFER_SIZE: usize = 1 << 20;
+
+/// turn a channel into a byte stream, like `Channel::into_stream`.
+/// `Channel::data` drops the data the server sends while it waits for the window,
+/// so only hand it what fits into the window and wait for the adjustment here instead.
+pub fn into_stream(channel: Channel<Msg>) -> DuplexStream{
+    let (stream, inner) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move{
+        let id = channel.id();
+        if let Err(e) = relay(channel, inner).await{
+            info!("channel {} stream failed:{}",id,e);
+        }
+    });
+    stream
+}
+
+/// copy between the channel and the other end of the duplex until either side closes
+async fn relay(mut channel: Channel<Msg>,mut inner: DuplexStream) -> std::result::Result<(),russh::Error>{
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop{
+        // keep the window above zero after the write, so `data` never waits for it
+        let writable = channel.writable_packet_size().saturating_sub(1).min(buf.len());
+        tokio::select!{
+            n = inner.read(&mut buf[..writable]), if writable > 0 =>{
+                match n{
+                    Ok(n) if n > 0 => channel.data(&buf[..n]).await?,
+                    _ =>{
+                        channel.eof().await?;
+                        channel.close().await?;
+                        break;
+                    }
+                }
+            }
+            msg = channel.wait() =>{
+                match msg{
+                    Some(ChannelMsg::Data{ data }) => match inner.write_all(&data).await{
+                        Ok(()) => {}
+                        Err(_) =>{
+                            channel.close().await?;
+                            break;
+                        }
+                    },
+                    Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+    info!("channel {} stream closed",channel.id());
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use russh::{ChannelMsg, client};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::Packet;
+use sha2::{Digest, Sha256};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use log::info;
+
+use crate::raw_sftp::{self, put_string};
+use crate::transfer::{self, TransferOptions};
+use crate::Client;
+
+/// passes everything read from `inner` through, hashing it on the way when enabled
+pub struct HashReader<R>{
+    inner: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R> HashReader<R>{
+    pub fn new(inner: R,enabled: bool) -> Self{
+        Self{ inner, hasher: enabled.then(Sha256::new) }
+    }
+    pub fn finish(self) -> Option<String>{
+        self.hasher.map(|h| to_hex(&h.finalize()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashReader<R>{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>>{
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(hasher) = self.hasher.as_mut(){
+            hasher.update(&buf.filled()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// passes everything written through to `inner`, hashing it on the way when enabled
+pub struct HashWriter<W>{
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W> HashWriter<W>{
+    pub fn new(inner: W,enabled: bool) -> Self{
+        Self{ inner, hasher: enabled.then(Sha256::new) }
+    }
+    pub fn finish(self) -> Option<String>{
+        self.hasher.map(|h| to_hex(&h.finalize()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W>{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>>{
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if let Some(hasher) = self.hasher.as_mut(){
+            hasher.update(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|b| format!("{:02x}",b)).collect()
+}
+
+pub async fn local_sha256(path: &str) -> std::result::Result<String,Box<dyn std::error::Error>>{
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop{
+        let n = file.read(&mut buf).await?;
+        if n == 0{
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// sha256 of a remote file, tried in order: the `check-file` sftp extension,
+/// `sha256sum` on an exec channel, and reading the file back over sftp
+pub async fn remote_sha256(ext: &raw_sftp::Extended<'_>,sftp: &SftpSession,path: &str,options: &TransferOptions) -> std::result::Result<String,Box<dyn std::error::Error>>{
+    match check_file(ext, path).await{
+        Ok(Some(hash)) => return Ok(hash),
+        Ok(None) => info!("check-file not supported"),
+        Err(e) => info!("check-file {} error:{}",path,e),
+    }
+    match sha256sum(ext.session, path).await{
+        Ok(hash) => return Ok(hash),
+        Err(e) => info!("sha256sum {} error:{}",path,e),
+    }
+    info!("read back {}",path);
+    let mut writer = HashWriter::new(tokio::io::sink(), true);
+    transfer::download(sftp, path, &mut writer, options).await?;
+    Ok(writer.finish().unwrap_or_default())
+}
+
+fn get_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]>{
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let s = data.get(4..4 + len)?;
+    *data = &data[4 + len..];
+    Some(s)
+}
+
+/// `check-file-name` from draft-ietf-secsh-filexfer
+async fn check_file(ext: &raw_sftp::Extended<'_>,path: &str) -> std::result::Result<Option<String>,Box<dyn std::error::Error>>{
+    if !ext.supports("check-file").await?{
+        return Ok(None);
+    }
+    let mut data = vec![];
+    put_string(&mut data, path.as_bytes());
+    put_string(&mut data, b"sha256");
+    // hash the whole file as one block
+    data.extend(0u64.to_be_bytes());
+    data.extend(0u64.to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    let reply = ext.raw().await?.extended("check-file-name", data).await?;
+    let Packet::ExtendedReply(reply) = reply else{
+        return Ok(None);
+    };
+    let mut data = reply.data.as_slice();
+    let _reply_type = get_string(&mut data);
+    match get_string(&mut data){
+        Some(b"sha256") if data.len() == 32 => Ok(Some(to_hex(data))),
+        _ => Err("bad check-file reply".into()),
+    }
+}
+
+async fn sha256sum(session: &client::Handle<Client>,path: &str) -> std::result::Result<String,Box<dyn std::error::Error>>{
+    let mut channel = session.channel_open_session().await?;
+    channel.exec(true, format!("sha256sum -- '{}'",path.replace('\'', "'\\''"))).await?;
+    let mut output = vec![];
+    let mut exit_status = None;
+    while let Some(msg) = channel.wait().await{
+        match msg{
+            ChannelMsg::Data{ data } => output.extend_from_slice(&data),
+            ChannelMsg::ExitStatus{ exit_status: status } => exit_status = Some(status),
+            ChannelMsg::Close => break,
+            _ => {}
+        }
+    }
+    let output = String::from_utf8_lossy(&output);
+    let hash = output.split_whitespace().next().unwrap_or("");
+    if exit_status != Some(0) || hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()){
+        return Err(format!("sha256sum exit status {:?}",exit_status).into());
+    }
+    Ok(hash.to_ascii_lowercase())
+}
@@ -1,10 +1,19 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use futures_util::StreamExt;
-use hyper::{Method, Request};
+use hyper::{Body, Method, Request, Response};
 use hyper::Client;
-use serde::{Serialize, Deserialize};
-use clap::Subcommand;
-#[cfg(feature = "vsock-support")]
-use hyper::{client::connect::Connect, Uri};
+use hyper::client::connect::Connect;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use clap::{Args, Subcommand, ValueEnum};
+use log::info;
+
+use crate::attach;
+use crate::auth::AuthOptions;
+use crate::endpoint::{EndpointConnector, EndpointOptions};
+use crate::history::{self, HistoryEntry};
+use crate::printer::Printer;
 
 
 #[derive(Serialize, Deserialize)]
@@ -13,7 +22,11 @@ pub struct RunCommandRequest{
     pub command: Vec<u8>,
     pub timeout: u32,
     pub kill_mode: bool,
-    pub client_token: String
+    pub client_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interpreter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>
 }
 #[derive(Serialize, Deserialize)]
 pub struct StopCommandRequest{
@@ -24,190 +37,892 @@ pub struct DescribeCommandRequest{
     pub command_id: String,
     pub output: bool
 }
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ListCommandsRequest{
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_token: Option<String>
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct RunCommandResponse{
+    pub command_id: String,
+    pub status: String
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct StopCommandResponse{
+    pub command_id: String,
+    pub status: String
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DescribeCommandResponse{
+    pub command_id: String,
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub output: String
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CommandSummary{
+    pub command_id: String,
+    pub status: String,
+    pub exit_code: Option<i32>,
+    /// unix seconds
+    pub created_at: Option<i64>,
+    pub client_token: String
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ListCommandsResponse{
+    pub commands: Vec<CommandSummary>
+}
+/// body of a non-2xx response
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ErrorResponse{
+    pub code: Option<String>,
+    pub error: String
+}
+
+impl DescribeCommandResponse{
+    /// the command will not produce more output
+    pub fn finished(&self) -> bool{
+        self.exit_code.is_some() || matches!(self.status.as_str(), "finished" | "failed" | "stopped" | "timeout" | "killed")
+    }
+}
+
+/// how responses are printed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat{
+    /// the response body as received
+    Raw,
+    /// the parsed response as json
+    Json,
+    /// aligned columns, followed by the command output
+    Table,
+}
+
+/// a response that can be printed as a row of a table
+trait Table{
+    fn header() -> &'static [&'static str];
+    fn row(&self) -> Vec<String>;
+    /// printed after the table
+    fn output(&self) -> Option<&str>{
+        None
+    }
+}
+
+impl Table for RunCommandResponse{
+    fn header() -> &'static [&'static str]{
+        &["COMMAND_ID", "STATUS"]
+    }
+    fn row(&self) -> Vec<String>{
+        vec![self.command_id.clone(), self.status.clone()]
+    }
+}
+
+impl Table for StopCommandResponse{
+    fn header() -> &'static [&'static str]{
+        &["COMMAND_ID", "STATUS"]
+    }
+    fn row(&self) -> Vec<String>{
+        vec![self.command_id.clone(), self.status.clone()]
+    }
+}
+
+impl Table for DescribeCommandResponse{
+    fn header() -> &'static [&'static str]{
+        &["COMMAND_ID", "STATUS", "EXIT_CODE"]
+    }
+    fn row(&self) -> Vec<String>{
+        vec![self.command_id.clone(), self.status.clone(), self.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string())]
+    }
+    fn output(&self) -> Option<&str>{
+        Some(self.output.as_str())
+    }
+}
+
+impl Table for CommandSummary{
+    fn header() -> &'static [&'static str]{
+        &["COMMAND_ID", "STATUS", "EXIT_CODE", "CREATED", "CLIENT_TOKEN"]
+    }
+    fn row(&self) -> Vec<String>{
+        let created = self.created_at
+            .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        vec![self.command_id.clone(), self.status.clone(), self.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string()), created, self.client_token.clone()]
+    }
+}
+
+impl Table for HistoryEntry{
+    fn header() -> &'static [&'static str]{
+        &["COMMAND_ID", "TIME", "TARGET", "COMMAND"]
+    }
+    fn row(&self) -> Vec<String>{
+        let time = chrono::DateTime::from_timestamp(self.time, 0)
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let mut command: String = self.command.lines().next().unwrap_or_default().chars().take(40).collect();
+        if command.len() < self.command.trim_end().len(){
+            command.push_str("...");
+        }
+        vec![self.command_id.clone(), time, self.target.clone(), command]
+    }
+}
+
+/// random id in the form of a version 4 uuid
+fn new_id() -> String{
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: String = b.iter().map(|x| format!("{:02x}",x)).collect();
+    format!("{}-{}-{}-{}-{}",&hex[..8],&hex[8..12],&hex[12..16],&hex[16..20],&hex[20..])
+}
+
+fn print_table<T: Table>(printer: &Printer,rows: &[T]) -> std::io::Result<()>{
+    let header = T::header();
+    let rows: Vec<Vec<String>> = rows.iter().map(|r| r.row()).collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| rows.iter().map(|r| r[i].len()).chain([header[i].len()]).max().unwrap_or(0))
+        .collect();
+    let line = |cells: Vec<&str>| cells.iter().zip(&widths).map(|(c, w)| format!("{:<w$}",c,w = *w)).collect::<Vec<_>>().join("  ").trim_end().to_string();
+    printer.line(&line(header.to_vec()))?;
+    for row in &rows{
+        printer.line(&line(row.iter().map(|c| c.as_str()).collect()))?;
+    }
+    Ok(())
+}
+
+/// one json object per line, a json array, or a table
+fn print_list<T: Serialize + Table>(printer: &Printer,format: OutputFormat,rows: &[T]) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    match format{
+        OutputFormat::Raw =>{
+            for row in rows{
+                printer.line(&serde_json::to_string(row)?)?;
+            }
+        },
+        OutputFormat::Json => printer.line(&serde_json::to_string(rows)?)?,
+        OutputFormat::Table => print_table(printer, rows)?,
+    }
+    Ok(())
+}
 
-#[derive(Subcommand, Debug)]
+/// unix seconds from rfc3339, unix seconds, or an age like 30s, 10m, 2h or 7d
+fn parse_time(s: &str) -> std::result::Result<i64,String>{
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s){
+        return Ok(time.timestamp());
+    }
+    if let Ok(seconds) = s.parse::<i64>(){
+        return Ok(seconds);
+    }
+    let bad = || format!("bad time \"{}\"",s);
+    let (i, unit) = s.char_indices().last().ok_or_else(bad)?;
+    let scale = match unit{
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return Err(bad()),
+    };
+    let age = s[..i].parse::<i64>().ok().filter(|age| *age >= 0).and_then(|age| age.checked_mul(scale)).ok_or_else(bad)?;
+    Ok(chrono::Utc::now().timestamp() - age)
+}
+
+/// print a parsed response as json or a table
+fn render<T: Serialize + Table>(printer: &Printer,format: OutputFormat,value: &T) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    if format == OutputFormat::Table{
+        print_table(printer, std::slice::from_ref(value))?;
+        if let Some(output) = value.output().filter(|o| !o.is_empty()){
+            printer.line("")?;
+            printer.write(output.as_bytes())?;
+            if !output.ends_with('\n'){
+                printer.line("")?;
+            }
+        }
+    }else{
+        printer.line(&serde_json::to_string(value)?)?;
+    }
+    Ok(())
+}
+
+#[derive(Subcommand, Debug, Clone)]
 pub enum ExecCommands{
+    #[command(flatten)]
+    Agent(AgentCommands),
+    /// list recent runs from the local history
+    History{
+        /// number of runs to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    }
+}
+
+/// the exec commands that talk to the agent of a target
+#[derive(Subcommand, Debug, Clone)]
+pub enum AgentCommands{
     /// run command
     Run{
-        /// command id
+        /// command id, generated and printed to stderr when absent
         #[arg(short, long)]
-        command_id: String,
+        command_id: Option<String>,
         /// exec command timeout
         #[arg(short, long)]
         timeout: u32,
         /// kill mode
         #[arg(short, long)]
         kill_mode: bool,
-        /// client_token mark unique
+        /// client_token mark unique, generated when absent
+        #[arg(short = 'T', long)]
+        client_token: Option<String>,
+        /// print the command output as it is produced until the command finishes
+        #[arg(short, long)]
+        follow: bool,
+        /// milliseconds between polls with --follow
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+        /// wait for the command to finish, print its output and exit with its exit code
+        #[arg(short, long, conflicts_with = "follow")]
+        wait: bool,
+        /// read the command from a file instead of stdin
+        #[arg(short = 'F', long, conflicts_with = "command")]
+        file: Option<PathBuf>,
+        /// program that runs the command on the remote side
         #[arg(short, long)]
-        client_token: String,
+        interpreter: Option<String>,
+        /// remote directory the command runs in
+        #[arg(short = 'd', long)]
+        working_dir: Option<String>,
+        /// command to run, read from stdin when absent. a single argument is sent as is,
+        /// several are shell-quoted and joined with spaces
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
     },
     /// describe command
     Describe{
         /// command id
         #[arg(short, long)]
         command_id: String,
+        /// print the command output as it is produced until the command finishes
+        #[arg(short, long)]
+        follow: bool,
+        /// milliseconds between polls with --follow
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
     },
     /// stop command
     Stop{
         /// command id
         #[arg(short, long)]
         command_id: String,
+    },
+    /// attach to a running command, relaying stdin, stdout and stderr until it exits, ctrl-] detaches on a terminal
+    Attach{
+        /// command id
+        #[arg(short, long)]
+        command_id: String,
+    },
+    /// list the commands the agent knows
+    List{
+        /// only commands in this status, like running or finished
+        #[arg(short, long)]
+        status: Option<String>,
+        /// only commands created since, rfc3339, unix seconds or an age like 10m, 2h, 7d
+        #[arg(long, value_parser = parse_time)]
+        since: Option<i64>,
+        /// only commands created until, same forms as --since
+        #[arg(long, value_parser = parse_time)]
+        until: Option<i64>,
+        /// only the command started with this client_token
+        #[arg(short = 'T', long)]
+        client_token: Option<String>,
+    },
+}
+
+/// timeouts and retries of requests to the agent
+#[derive(Args, Debug, Clone)]
+pub struct HttpOptions{
+    /// seconds to wait for each response
+    #[arg(long, default_value_t = 30, global = true)]
+    pub request_timeout: u64,
+    /// times a failed request is retried, with exponential backoff
+    #[arg(long, default_value_t = 3, global = true)]
+    pub retries: u32,
+}
+
+impl HttpOptions{
+    fn request_timeout(&self) -> Duration{
+        Duration::from_secs(self.request_timeout)
+    }
+}
+
+/// the agent did not answer in time
+#[derive(Debug)]
+struct TimedOut(String);
+
+impl std::fmt::Display for TimedOut{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TimedOut{}
+
+/// the agent did not answer in time, or the connection to it was not made within --connect-timeout
+fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool{
+    std::iter::successors(Some(e), |e| e.source()).any(|e|{
+        e.is::<TimedOut>() || e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+    })
+}
+
+/// how a target of a fan-out ended
+enum Outcome{
+    /// the exit code of the command, if the subcommand has one
+    Exit(Option<i32>),
+    /// no answer or no connection in time, or the command outlived --timeout
+    TimedOut,
+    Failed,
+}
+
+/// an ops http endpoint and how to reach it
+struct Agent<C>{
+    client: Client<C,Body>,
+    uuu: String,
+    options: HttpOptions,
+    auth: AuthOptions,
+}
+
+fn post<C,T: Serialize>(agent: &Agent<C>,path: &str,request: &T) -> std::result::Result<Request<Body>,Box<dyn std::error::Error>>{
+    let body = serde_json::to_vec(request)?;
+    let mut builder = Request::builder()
+        .method(Method::POST)
+        .header("content-type" ,"application/json")
+        .uri(format!("{}{}",agent.uuu,path));
+    // signed again on every attempt, the timestamp changes
+    for (name, value) in agent.auth.headers("POST", path, &body){
+        builder = builder.header(name, value);
     }
+    Ok(builder.body(body.into())?)
 }
-#[cfg(feature = "vsock-support")]
-fn new_vsock_client(cid: Option<String>) -> (Client<impl Connect + Clone,hyper::Body>,String){
-    use tokio_vsock::VsockStream;
 
-    use futures::future::BoxFuture;
-    use std::{
-        pin::Pin,
-        task::{Context, Poll},
+/// send a request to the agent, a non-2xx status becomes an error with the status and body.
+/// failed connections, timeouts and 5xx responses are retried, a repeated run is
+/// recognised by the agent through its client_token
+async fn call<C,T>(agent: &Agent<C>,path: &str,request: &T) -> std::result::Result<Response<Body>,Box<dyn std::error::Error>>
+where C: Connect + Clone + Send + Sync + 'static, T: Serialize{
+    let name = path.trim_start_matches("/ops/");
+    let mut delay = Duration::from_millis(200);
+    let mut attempt = 0;
+    let resp = loop{
+        let last = attempt >= agent.options.retries;
+        attempt += 1;
+        match tokio::time::timeout(agent.options.request_timeout(), agent.client.request(post(agent, path, request)?)).await{
+            Ok(Ok(resp)) if last || !resp.status().is_server_error() => break resp,
+            Ok(Ok(resp)) => info!("{}: {}, attempt {}",name,resp.status(),attempt),
+            Ok(Err(e)) if last => return Err(e.into()),
+            Ok(Err(e)) => info!("{}: {}, attempt {}",name,e,attempt),
+            Err(_) if last => return Err(TimedOut(format!("{}: no response within {}s",name,agent.options.request_timeout)).into()),
+            Err(_) => info!("{}: timed out, attempt {}",name,attempt),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(5));
     };
-    use tokio::io::{ReadBuf,AsyncRead,AsyncWrite};
-    #[derive(Clone)]
-    pub struct VsockConnector{
+    let status = resp.status();
+    if status.is_success(){
+        return Ok(resp);
     }
-    pub struct VsockStreamConnection{
-        pub stream: VsockStream
+    let body = read_body(agent, path, resp).await?;
+    let message = match serde_json::from_slice::<ErrorResponse>(&body){
+        Ok(ErrorResponse{ code: Some(code), error }) => format!("{}: {}",code,error),
+        Ok(ErrorResponse{ code: None, error }) if !error.is_empty() => error,
+        _ => String::from_utf8_lossy(&body).trim().to_string(),
+    };
+    Err(format!("{}: {} {}",name,status,message).into())
+}
+
+async fn read_body<C>(agent: &Agent<C>,path: &str,resp: Response<Body>) -> std::result::Result<hyper::body::Bytes,Box<dyn std::error::Error>>{
+    match tokio::time::timeout(agent.options.request_timeout(), hyper::body::to_bytes(resp.into_body())).await{
+        Ok(body) => Ok(body?),
+        Err(_) => Err(TimedOut(format!("{}: no response within {}s",path.trim_start_matches("/ops/"),agent.options.request_timeout)).into()),
     }
-    impl VsockConnector{
-        pub fn new() ->Self{
-            Self{}
+}
+
+async fn call_json<C,T,R>(agent: &Agent<C>,path: &str,request: &T) -> std::result::Result<R,Box<dyn std::error::Error>>
+where C: Connect + Clone + Send + Sync + 'static, T: Serialize, R: DeserializeOwned{
+    let resp = call(agent, path, request).await?;
+    let body = read_body(agent, path, resp).await?;
+    serde_json::from_slice(&body).map_err(|e| format!("{}: bad response: {}: {}",path.trim_start_matches("/ops/"),e,String::from_utf8_lossy(&body)).into())
+}
+
+/// print the response in the requested format, raw bodies are copied as they arrive
+async fn print_response<C,T,R>(agent: &Agent<C>,path: &str,request: &T,format: OutputFormat,printer: &Printer) -> std::result::Result<(),Box<dyn std::error::Error>>
+where C: Connect + Clone + Send + Sync + 'static, T: Serialize, R: DeserializeOwned + Serialize + Table{
+    if format == OutputFormat::Raw{
+        return print_body(call(agent, path, request).await?, printer).await;
+    }
+    render(printer, format, &call_json::<C,T,R>(agent, path, request).await?)
+}
+
+/// copy the whole response body to stdout as it arrives
+async fn print_body(resp: Response<Body>,printer: &Printer) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let mut body = resp.into_body();
+    let mut last = b'\n';
+    while let Some(chunk) = body.next().await{
+        let chunk = chunk?;
+        if let Some(byte) = chunk.last(){
+            last = *byte;
         }
+        printer.write(&chunk)?;
+    }
+    if last != b'\n'{
+        printer.line("")?;
     }
-    impl tower_service::Service<Uri> for VsockConnector{
-        type Response = VsockStreamConnection;
-        type Error = std::io::Error;
-        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
-        fn call(&mut self, req: Uri) -> Self::Future {
+    Ok(())
+}
 
-            let fut = async move{
-                let cid = u32::from_str_radix(req.host().unwrap(),10).unwrap();
-                println!("cid:{}",cid);
-                let stream = VsockStream::connect(cid, req.port_u16().unwrap() as u32).await?;
-                Ok(VsockStreamConnection{stream})
-            };
-            Box::pin(fut)
+async fn describe_command<C>(agent: &Agent<C>,command_id: &str) -> std::result::Result<DescribeCommandResponse,Box<dyn std::error::Error>>
+where C: Connect + Clone + Send + Sync + 'static{
+    let request = DescribeCommandRequest{
+        command_id: command_id.to_string(),
+        output: true
+    };
+    call_json(agent, "/ops/describe_command", &request).await
+}
+
+/// poll describe and print the output the command produced since the last poll, until it finishes
+async fn follow<C>(agent: &Agent<C>,command_id: &str,interval: u64,printer: &Printer) -> std::result::Result<DescribeCommandResponse,Box<dyn std::error::Error>>
+where C: Connect + Clone + Send + Sync + 'static{
+    let mut seen = String::new();
+    loop{
+        let describe = describe_command(agent, command_id).await?;
+        let (new, truncated) = unseen(describe.output.as_bytes(), seen.as_bytes());
+        if truncated{
+            printer.error("output truncated by the agent, printing what it still has");
         }
-        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-            Poll::Ready(Ok(()))
+        printer.write(new)?;
+        seen.clone_from(&describe.output);
+        if describe.finished(){
+            info!("command {} {} exit code {:?}",command_id,describe.status,describe.exit_code);
+            return Ok(describe);
         }
+        tokio::time::sleep(Duration::from_millis(interval)).await;
     }
-    impl hyper::client::connect::Connection for VsockStreamConnection {
-        fn connected(&self) -> hyper::client::connect::Connected {
-            hyper::client::connect::Connected::new()
-        }
+}
+
+/// the agent returns all the output it still holds every time, `seen` is what the last poll returned.
+/// a growing buffer starts with `seen`, a rolling one dropped its start and begins with the end of it,
+/// the new part follows the longest such overlap. no overlap at all means more was dropped than was printed.
+/// output that repeats itself can look overlapped further than it is, the agent gives no offset to tell
+fn unseen<'a>(output: &'a [u8],seen: &[u8]) -> (&'a [u8],bool){
+    if output.starts_with(seen){
+        return (&output[seen.len()..], false);
+    }
+    let overlap = overlap(seen, output);
+    (&output[overlap..], overlap == 0)
+}
+
+/// length of the longest start of `output` that `seen` ends with, knuth-morris-pratt over `seen`
+fn overlap(seen: &[u8],output: &[u8]) -> usize{
+    if output.is_empty(){
+        return 0;
     }
-    impl AsyncRead for VsockStreamConnection{
-        fn poll_read(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &mut ReadBuf<'_>,
-        ) -> Poll<std::io::Result<()>>{
-            Pin::new(&mut self.stream).poll_read(cx, buf)
+    // fallback[i], the longest proper start of output[..=i] that is also its end
+    let mut fallback = vec![0; output.len()];
+    let mut k = 0;
+    for i in 1..output.len(){
+        while k > 0 && output[i] != output[k]{
+            k = fallback[k - 1];
         }
+        if output[i] == output[k]{
+            k += 1;
+        }
+        fallback[i] = k;
     }
-    impl AsyncWrite for VsockStreamConnection{
-        fn poll_write(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &[u8],
-        ) -> Poll<std::result::Result<usize, std::io::Error>>{
-            Pin::new(&mut self.stream).poll_write(cx, buf)
+    let mut k = 0;
+    for &byte in seen{
+        while k > 0 && (k == output.len() || byte != output[k]){
+            k = fallback[k - 1];
+        }
+        if byte == output[k]{
+            k += 1;
         }
-        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::result::Result<(), std::io::Error>>{
-            Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    k
+}
+
+/// poll describe with a growing delay until the command finishes, giving up some time after `timeout` seconds
+async fn wait<C>(agent: &Agent<C>,command_id: &str,timeout: u32) -> std::result::Result<Option<DescribeCommandResponse>,Box<dyn std::error::Error>>
+where C: Connect + Clone + Send + Sync + 'static{
+    // the agent needs a moment to kill the command and report the timeout itself
+    let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_secs(timeout as u64 + 5));
+    let mut delay = Duration::from_millis(100);
+    loop{
+        let describe = describe_command(agent, command_id).await?;
+        if describe.finished(){
+            info!("command {} {} exit code {:?}",command_id,describe.status,describe.exit_code);
+            return Ok(Some(describe));
         }
-        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::result::Result<(), std::io::Error>>{
-            Pin::new(&mut self.stream).poll_shutdown(cx)
+        if deadline.is_some_and(|deadline| Instant::now() + delay > deadline){
+            return Ok(None);
         }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(2));
     }
-    let client = Client::builder().build::<VsockConnector,hyper::Body>(VsockConnector::new());
-    let mut url = "vsock://".to_string();
-    url.push_str(cid.as_ref().unwrap());
-    url.push_str(":1027");
-    (client,url)
+}
 
+/// a failure to write the history does not fail the run
+async fn record(entry: HistoryEntry){
+    match tokio::task::spawn_blocking(move || history::append(entry).map_err(|e| e.to_string())).await{
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => info!("history: {}",e),
+        Err(e) => info!("history: {}",e),
+    }
 }
 
+/// the bytes of the command to run, given as arguments, in a file or on stdin,
+/// read once before they are sent to each target. empty for the other commands
+fn read_script(agent_commands: &AgentCommands) -> std::result::Result<Vec<u8>,Box<dyn std::error::Error>>{
+    let AgentCommands::Run { file, command, .. } = agent_commands else{
+        return Ok(vec![]);
+    };
+    // sent exactly as given, scripts keep their newlines
+    let script = if command.len() == 1{
+        command[0].clone().into_bytes()
+    }else if !command.is_empty(){
+        command.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" ").into_bytes()
+    }else if let Some(file) = file{
+        std::fs::read(file).map_err(|e| format!("{}: {}",file.display(),e))?
+    }else{
+        let mut buf = vec![];
+        std::io::stdin().read_to_end(&mut buf)?;
+        buf
+    };
+    if script.is_empty(){
+        return Err("empty command".into());
+    }
+    Ok(script)
+}
 
-pub async fn command_loop(
-    #[cfg(feature="vsock-support")]
-    cid: Option<String>, 
-    exec_commands: ExecCommands) -> std::result::Result<(),Box<dyn std::error::Error>>{
-    //------------------------------------------------------------------------------------------------------------------------
-    // over tcp
-    #[cfg(not(feature = "vsock-support"))]
-    let (client,uuu) = (Client::new(), "http://localhost:7777".to_string());
-    // let tcp_socket = tokio::net::TcpSocket::new_v4().unwrap();
-    // let stream = tcp_socket.connect("127.0.0.1:7777".parse().unwrap()).await.unwrap();
-    //------------------------------------------------------------------------------------------------------------------------
-    #[cfg(feature = "vsock-support")]
-    let (client,uuu) = new_vsock_client(cid);
-    
+/// `arg` as one word for sh, quoted only when it has to be
+fn shell_quote(arg: &str) -> String{
+    if !arg.is_empty() && arg.bytes().all(|b| b.is_ascii_alphanumeric() || b"_-+=%@:,./".contains(&b)){
+        return arg.to_string();
+    }
+    format!("'{}'",arg.replace('\'', "'\\''"))
+}
+
+/// targets separated by commas, or one per line in the file after `@`
+fn parse_targets(spec: &str) -> std::result::Result<Vec<String>,Box<dyn std::error::Error>>{
+    let targets: Vec<String> = match spec.strip_prefix('@'){
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{}: {}",path,e))?
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect(),
+        None => spec.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()).map(|t| t.to_string()).collect(),
+    };
+    if targets.is_empty(){
+        return Err(format!("no targets in \"{}\"",spec).into());
+    }
+    Ok(targets)
+}
+
+/// run the exec command against every target, at most `parallel` at a time,
+/// returns the exit code the process should exit with, if any
+pub async fn exec_loop(
+    endpoint: &EndpointOptions,
+    parallel: usize,
+    options: HttpOptions,
+    auth: AuthOptions,
+    format: OutputFormat,
+    exec_commands: ExecCommands) -> std::result::Result<Option<i32>,Box<dyn std::error::Error>>{
+    let agent_commands = match exec_commands{
+        // the history is local, it needs no target
+        ExecCommands::History { limit } =>{
+            print_list(&Printer::new(None), format, &history::recent(limit)?)?;
+            return Ok(None);
+        },
+        ExecCommands::Agent(agent_commands) => agent_commands,
+    };
+    let script = read_script(&agent_commands)?;
+    let targets = parse_targets(&endpoint.target()?)?;
+    if targets.len() > 1 && matches!(agent_commands, AgentCommands::Attach { .. }){
+        return Err("attach takes a single target".into());
+    }
+    if targets.len() == 1{
+        let printer = Printer::new(None);
+        return match command_loop(endpoint, &targets[0], options, auth, format, agent_commands, &script, &printer).await{
+            // like timeout(1), and like a target that ran out of time in a fan-out
+            Err(e) if is_timeout(e.as_ref()) =>{
+                info!("exec command error:{:?}",e);
+                printer.error(&format!("exec: {}",e));
+                Ok(Some(124))
+            },
+            res => res,
+        };
+    }
+    let results: Vec<(String, Outcome)> = futures_util::stream::iter(targets)
+        .map(|target|{
+            let agent_commands = agent_commands.clone();
+            let script = &script;
+            let options = options.clone();
+            let auth = auth.clone();
+            async move{
+                let printer = Printer::new(Some(&target));
+                let res = command_loop(endpoint, &target, options, auth, format, agent_commands, script, &printer).await;
+                let _ = printer.finish();
+                let res = match res{
+                    Ok(code) => Outcome::Exit(code),
+                    Err(e) =>{
+                        info!("{}: exec command error:{:?}",target,e);
+                        printer.error(&e.to_string());
+                        if is_timeout(e.as_ref()) { Outcome::TimedOut }else{ Outcome::Failed }
+                    },
+                };
+                (target, res)
+            }
+        })
+        .buffer_unordered(parallel.max(1))
+        .collect()
+        .await;
+    let mut failed = vec![];
+    let mut timed_out = vec![];
+    for (target, res) in &results{
+        match res{
+            Outcome::Exit(None) | Outcome::Exit(Some(0)) => {},
+            // a remote exit code of 124 is the command's own, not a timeout here
+            Outcome::Exit(Some(code)) => failed.push(format!("{} (exit {})",target,code)),
+            Outcome::TimedOut => timed_out.push(target.clone()),
+            Outcome::Failed => failed.push(target.clone()),
+        }
+    }
+    eprintln!("{} succeeded, {} failed, {} timed out",results.len() - failed.len() - timed_out.len(),failed.len(),timed_out.len());
+    if !failed.is_empty(){
+        eprintln!("failed: {}",failed.join(", "));
+    }
+    if !timed_out.is_empty(){
+        eprintln!("timed out: {}",timed_out.join(", "));
+    }
+    Ok(Some(if failed.is_empty() && timed_out.is_empty() { 0 }else{ 1 }))
+}
+
+/// returns the exit code the process should exit with, if any
+#[allow(clippy::too_many_arguments)]
+async fn command_loop(
+    endpoint_options: &EndpointOptions,
+    target: &str,
+    options: HttpOptions,
+    auth: AuthOptions,
+    format: OutputFormat,
+    agent_commands: AgentCommands,
+    script: &[u8],
+    printer: &Printer) -> std::result::Result<Option<i32>,Box<dyn std::error::Error>>{
+    let mut endpoint = endpoint_options.parse(target)?;
+    auth.apply(&mut endpoint)?;
+    let connector = EndpointConnector{
+        endpoint: endpoint.clone(),
+        tls: auth.connector(&endpoint)?,
+    };
+    let agent = Agent{ client: Client::builder().build(connector), uuu: endpoint.http_url(), options, auth };
     //------------------------------------------------------------------------------------------------------------------------
-    match exec_commands{
-        ExecCommands::Run { command_id, timeout, kill_mode, client_token } =>{
-            let mut line = String::new();
-            let _ = std::io::stdin().read_line(&mut line).unwrap();
-            let command = line.trim();
+    match agent_commands{
+        AgentCommands::Run { command_id, timeout, kill_mode, client_token, follow: follow_output, interval, wait: wait_exit, interpreter, working_dir, .. } =>{
+            let command_id = command_id.unwrap_or_else(||{
+                let id = new_id();
+                printer.error(&format!("command_id: {}",id));
+                id
+            });
+            let client_token = client_token.unwrap_or_else(new_id);
+            let entry = HistoryEntry{
+                command_id: command_id.clone(),
+                client_token: client_token.clone(),
+                target: target.to_string(),
+                time: chrono::Utc::now().timestamp(),
+                command: String::from_utf8_lossy(script).into_owned(),
+            };
             let request = RunCommandRequest{
-                command_id: command_id,
-                command: command.as_bytes().to_vec(),
-                timeout: timeout,
-                kill_mode: kill_mode,
-                client_token: client_token
+                command_id: command_id.clone(),
+                command: script.to_vec(),
+                timeout,
+                kill_mode,
+                client_token,
+                interpreter,
+                working_dir
+            };
+            if !follow_output && !wait_exit{
+                print_response::<_,_,RunCommandResponse>(&agent, "/ops/run_command", &request, format, printer).await?;
+                record(entry).await;
+                return Ok(None);
+            }
+            let run: RunCommandResponse = call_json(&agent, "/ops/run_command", &request).await?;
+            info!("run_command: {:?}",run);
+            record(entry).await;
+            if follow_output{
+                follow(&agent, &command_id, interval, printer).await?;
+                return Ok(None);
+            }
+            let Some(describe) = wait(&agent, &command_id, timeout).await? else{
+                return Err(TimedOut(format!("command {} did not finish within {}s",command_id,timeout)).into());
             };
-            let req = Request::builder()
-                .method(Method::POST)
-                .header("content-type" ,"application/json")
-                .uri(uuu.clone() + "/ops/run_command")
-                .body(serde_json::to_vec(&request).unwrap().into())
-                .expect("request builder");
-            let mut resp = client.request(req).await?;
-            if resp.status().is_success(){
-                if let Some(Ok(body)) = resp.body_mut().next().await{
-                    println!("{}",String::from_utf8_lossy(&body.to_vec()).into_owned());
-                }
+            if format == OutputFormat::Raw{
+                printer.write(describe.output.as_bytes())?;
+            }else{
+                render(printer, format, &describe)?;
             }
+            // stopped or killed commands may not report an exit code
+            return Ok(Some(describe.exit_code.unwrap_or(1)));
         },
-        ExecCommands::Describe { command_id } =>{
-    
+        AgentCommands::Describe { command_id, follow: follow_output, interval } =>{
+            if follow_output{
+                follow(&agent, &command_id, interval, printer).await?;
+                return Ok(None);
+            }
             let request = DescribeCommandRequest{
                 command_id: command_id.to_string(),
                 output: true
             };
-            let req = Request::builder()
-                .method(Method::POST)
-                .header("content-type" ,"application/json")
-                .uri(uuu.clone() +"/ops/describe_command")
-                .body(serde_json::to_vec(&request).unwrap().into())
-                .expect("request builder");
-            let mut resp = client.request(req).await?;
-            if resp.status().is_success(){
-                if let Some(Ok(body)) = resp.body_mut().next().await{
-                    println!("{}",String::from_utf8_lossy(&body.to_vec()).into_owned());
-                }
-            }
+            print_response::<_,_,DescribeCommandResponse>(&agent, "/ops/describe_command", &request, format, printer).await?;
         },
-        ExecCommands::Stop { command_id } =>{
+        AgentCommands::Stop { command_id } =>{
             let request = StopCommandRequest{
                 command_id: command_id.to_string()
             };
-            let req = Request::builder()
-                .method(Method::POST)
-                .header("content-type" ,"application/json")
-                .uri(uuu.clone() +"/ops/stop_command")
-                .body(serde_json::to_vec(&request).unwrap().into())
-                .expect("request builder");
-            let mut resp = client.request(req).await?;
-            if resp.status().is_success(){
-                if let Some(Ok(body)) = resp.body_mut().next().await{
-                    println!("{}",String::from_utf8_lossy(&body.to_vec()).into_owned());
-                }
+            print_response::<_,_,StopCommandResponse>(&agent, "/ops/stop_command", &request, format, printer).await?;
+        },
+        AgentCommands::Attach { command_id } =>{
+            let code = attach::attach_loop(
+                &endpoint,
+                &command_id,
+                &agent.auth
+            ).await?;
+            if code.is_none(){
+                printer.error(&format!("\r\ndetached from {}",command_id));
             }
+            return Ok(code);
+        },
+        AgentCommands::List { status, since, until, client_token } =>{
+            let request = ListCommandsRequest{ status, since, until, client_token };
+            let list: ListCommandsResponse = call_json(&agent, "/ops/list_commands", &request).await?;
+            // in case the agent ignores some of the filters
+            let mut commands: Vec<CommandSummary> = list.commands.into_iter()
+                .filter(|c| request.status.as_ref().is_none_or(|s| &c.status == s))
+                .filter(|c| request.client_token.as_ref().is_none_or(|t| &c.client_token == t))
+                .filter(|c| request.since.is_none_or(|since| c.created_at.is_some_and(|t| t >= since)))
+                .filter(|c| request.until.is_none_or(|until| c.created_at.is_some_and(|t| t <= until)))
+                .collect();
+            commands.sort_by_key(|c| c.created_at);
+            print_list(printer, format, &commands)?;
+        },
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parse_time_absolute(){
+        assert_eq!(parse_time("1700000000"), Ok(1700000000));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Ok(1700000000));
+        assert_eq!(parse_time("2023-11-15T06:13:20+08:00"), Ok(1700000000));
+    }
+
+    /// an error with a cause, like hyper's around a failed connect
+    #[derive(Debug)]
+    struct Wrapped(std::io::Error);
+
+    impl std::fmt::Display for Wrapped{
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+            write!(f, "error trying to connect: {}",self.0)
         }
     }
-    Ok(())
-}
\ No newline at end of file
+
+    impl std::error::Error for Wrapped{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn timeouts(){
+        let connect = std::io::Error::new(std::io::ErrorKind::TimedOut, "agent:7777: connect timed out");
+        assert!(is_timeout(&TimedOut("describe_command: no response within 30s".into())));
+        assert!(is_timeout(&Wrapped(connect)));
+        assert!(!is_timeout(&Wrapped(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))));
+        assert!(!is_timeout(Box::<dyn std::error::Error>::from("run_command: 500 Internal Server Error").as_ref()));
+    }
+
+    #[test]
+    fn parse_time_ages(){
+        let now = chrono::Utc::now().timestamp();
+        for (age, seconds) in [("30s", 30), ("10m", 600), ("2h", 7200), ("7d", 604800)]{
+            let time = parse_time(age).unwrap();
+            assert!((now - seconds..=now - seconds + 5).contains(&time), "{} gave {}",age,time);
+        }
+    }
+
+    #[test]
+    fn parse_time_rejects_garbage(){
+        for bad in ["", "m", "10x", "-5m", "1.5h", "10é", "é", "99999999999999999d"]{
+            assert!(parse_time(bad).is_err(), "{} was accepted",bad);
+        }
+    }
+
+    #[test]
+    fn shell_quote_words(){
+        assert_eq!(shell_quote("ls"), "ls");
+        assert_eq!(shell_quote("-l"), "-l");
+        assert_eq!(shell_quote("/tmp/a.txt"), "/tmp/a.txt");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my file"), "'my file'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn unseen_growing_output(){
+        assert_eq!(unseen(b"hello", b""), (&b"hello"[..], false));
+        assert_eq!(unseen(b"hello", b"hel"), (&b"lo"[..], false));
+        assert_eq!(unseen(b"hello", b"hello"), (&b""[..], false));
+    }
+
+    #[test]
+    fn unseen_rolling_output(){
+        // a buffer of 8 bytes that kept its size while 3 more came in
+        assert_eq!(unseen(b"45678abc", b"12345678"), (&b"abc"[..], false));
+        assert_eq!(unseen(b"line 2\nline 3\n", b"line 1\nline 2\n"), (&b"line 3\n"[..], false));
+        // shrunk to its end
+        assert_eq!(unseen(b"lo", b"hello"), (&b""[..], false));
+    }
+
+    #[test]
+    fn unseen_rolled_past_everything_seen(){
+        assert_eq!(unseen(b"abcdefgh", b"12345678"), (&b"abcdefgh"[..], true));
+        assert_eq!(unseen(b"", b"hello"), (&b""[..], true));
+    }
+
+    #[test]
+    fn unseen_output_splits_multibyte_chars(){
+        let output = "héllo".as_bytes();
+        assert_eq!(unseen(output, &output[..2]), (&output[2..], false));
+    }
+
+    #[test]
+    fn overlap_lengths(){
+        assert_eq!(overlap(b"abcab", b"abx"), 2);
+        assert_eq!(overlap(b"aaaa", b"aab"), 2);
+        assert_eq!(overlap(b"abab", b"abab"), 4);
+        assert_eq!(overlap(b"abc", b""), 0);
+        assert_eq!(overlap(b"", b"abc"), 0);
+    }
+}
@@ -0,0 +1,91 @@
+use russh::{Channel, client, client::Msg};
+use russh_sftp::client::SftpSession;
+use tokio::io::AsyncWriteExt;
+use log::info;
+
+use crate::{checksum, raw_sftp, sftp, Client};
+use crate::progress::ProgressReader;
+use crate::sftp::{base_name, remote_path};
+use crate::transfer::{self, TransferOptions};
+
+/// one end of a copy, a sftp session and the ssh session it runs on
+struct Side<'a>{
+    ext: raw_sftp::Extended<'a>,
+    sftp: SftpSession,
+}
+
+/// copy `from` on this target to `to` on the destination target, streaming the data
+/// from one sftp session into the other without touching local disk
+pub async fn copy_loop(from: String,to: String,recursive: bool,options: TransferOptions,session: &client::Handle<Client>,channel: Channel<Msg>,dest: &client::Handle<Client>) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let src = Side{ ext: raw_sftp::Extended::new(session), sftp: sftp::open_session(channel, &options).await? };
+    let dst = Side{ ext: raw_sftp::Extended::new(dest), sftp: sftp::open_session(dest.channel_open_session().await?, &options).await? };
+    let attrs = src.sftp.metadata(from.as_str()).await?;
+    // like cp, an existing destination directory receives the source under its own name
+    let target = if dst.sftp.metadata(to.as_str()).await.map(|m| m.is_dir()).unwrap_or(false){
+        remote_path(&to, base_name(&from))
+    }else{
+        to.clone()
+    };
+    if !attrs.is_dir(){
+        return copy_file(&src, &from, &dst, &target, attrs.size.unwrap_or(0), &options).await;
+    }
+    if !recursive{
+        return Err(format!("{}: is a directory, use --recursive",from).into());
+    }
+    let mut dirs = vec![(from, target)];
+    while let Some((src_dir, dst_dir)) = dirs.pop(){
+        if !dst.sftp.try_exists(dst_dir.as_str()).await?{
+            dst.sftp.create_dir(dst_dir.as_str()).await?;
+        }
+        let mut entries: Vec<_> = src.sftp.read_dir(src_dir.as_str()).await?.collect();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries{
+            let src_path = remote_path(&src_dir, &entry.file_name());
+            let dst_path = remote_path(&dst_dir, &entry.file_name());
+            let file_type = entry.file_type();
+            if file_type.is_dir(){
+                dirs.push((src_path, dst_path));
+            }else if file_type.is_file(){
+                copy_file(&src, &src_path, &dst, &dst_path, entry.metadata().size.unwrap_or(0), &options).await?;
+            }else if file_type.is_symlink(){
+                let link_target = src.sftp.read_link(src_path.as_str()).await?;
+                raw_sftp::symlink(&dst.ext, &dst.sftp, &dst_path, &link_target).await?;
+            }else{
+                info!("skip {}",src_path);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn copy_file(src: &Side<'_>,from: &str,dst: &Side<'_>,to: &str,size: u64,options: &TransferOptions) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    info!("copy {} to {}",from,to);
+    // enough room for every read in flight on the source side
+    let (mut writer, reader) = tokio::io::duplex(options.window()?);
+    let mut reader = ProgressReader::new(reader, to.to_string(), size);
+    let download = async{
+        transfer::download(&src.sftp, from, &mut writer, options).await?;
+        // the upload side sees the end of the file
+        writer.shutdown().await?;
+        Ok::<_,Box<dyn std::error::Error>>(())
+    };
+    let upload = async{
+        if options.atomic{
+            sftp::atomic_upload(&dst.ext, &dst.sftp, &mut reader, to, Some(size), options).await
+        }else{
+            transfer::upload(&dst.sftp, &mut reader, to, Some(size), options).await.map(|_| ())
+        }
+    };
+    futures::try_join!(download, upload)?;
+    if options.verify{
+        let (src_hash, dst_hash) = futures::try_join!(
+            checksum::remote_sha256(&src.ext, &src.sftp, from, options),
+            checksum::remote_sha256(&dst.ext, &dst.sftp, to, options)
+        )?;
+        if src_hash != dst_hash{
+            return Err(format!("sha256 mismatch: {} is {}, {} is {}",from,src_hash,to,dst_hash).into());
+        }
+        eprintln!("sha256 verified: {}  {}",dst_hash,to);
+    }
+    Ok(())
+}
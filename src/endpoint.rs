@@ -0,0 +1,598 @@
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use clap::{Args, ValueEnum};
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use hyper::Uri;
+use hyper::client::connect::{Connected, Connection};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "vsock-support")]
+use tokio_vsock::VsockStream;
+use log::info;
+
+/// port of the agent on vsock
+pub const VSOCK_PORT: u32 = 1027;
+
+/// the agent's address when none is given
+const DEFAULT_HOST: &str = "127.0.0.1:7777";
+
+/// the sshd reached with --transport tcp when no --host is given
+const DEFAULT_SSH_HOST: &str = "127.0.0.1:22";
+
+/// the host the websocket of the agent at `DEFAULT_HOST` has always been asked for,
+/// the agent's own port behind the forward on 7777
+const DEFAULT_WS_AUTHORITY: &str = "127.0.0.1:1077";
+
+/// seconds to wait for the connection when not told otherwise
+const CONNECT_TIMEOUT: u64 = 10;
+
+/// how long a connection attempt gets before the next address is tried alongside it, as in rfc 8305
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// the transport a `scheme://` in front of a target asks for
+fn scheme_transport(scheme: &str) -> Option<Transport>{
+    Some(match scheme.to_ascii_lowercase().as_str(){
+        "ws" => Transport::Ws,
+        "wss" | "tls" => Transport::Wss,
+        "vsock" => Transport::Vsock,
+        "unix" => Transport::Unix,
+        // like --transport tcp
+        "ssh" | "tcp" => Transport::Tcp,
+        _ => return None,
+    })
+}
+
+/// how the bytes reach the target
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport{
+    /// ssh straight over tcp, without the agent's websocket
+    Tcp,
+    /// the agent's websocket over tcp
+    Ws,
+    /// the agent's websocket over tls
+    Wss,
+    /// the agent's websocket over vsock, when built with vsock support
+    Vsock,
+    /// the agent's websocket over a unix socket
+    Unix,
+    /// ssh over the stdin and stdout of the command given in --host, like ProxyCommand
+    Stdio,
+}
+
+impl Transport{
+    /// whether the ssh stream is wrapped in the agent's websocket
+    pub fn websocket(self) -> bool{
+        !matches!(self, Transport::Tcp | Transport::Stdio)
+    }
+}
+
+/// where the target is and how to reach it
+#[derive(Args, Debug, Clone, Default)]
+pub struct EndpointOptions{
+    /// how to reach the target, vsock when only --cid is given, ws otherwise
+    #[arg(long, value_enum)]
+    pub transport: Option<Transport>,
+    /// host:port, 127.0.0.1:7777 or with --transport tcp 127.0.0.1:22 when not given, the socket path with --transport unix
+    /// or the command with --transport stdio, exec also takes a comma separated list or @file with one per line.
+    /// ssh:// or tcp://, ws://, wss:// or tls://, vsock:// and unix: in front pick the transport of that target
+    #[arg(long)]
+    pub host: Option<String>,
+    /// cid[:port] for --transport vsock, exec also takes a comma separated list or @file with one per line
+    #[arg(short, long)]
+    pub cid: Option<String>,
+    /// seconds to wait for the connection to the target
+    #[arg(long, default_value_t = CONNECT_TIMEOUT, global = true)]
+    pub connect_timeout: u64,
+    /// only connect to ipv4 addresses
+    #[arg(short = '4', long, global = true, conflicts_with = "ipv6")]
+    pub ipv4: bool,
+    /// only connect to ipv6 addresses
+    #[arg(short = '6', long, global = true)]
+    pub ipv6: bool,
+}
+
+impl EndpointOptions{
+    pub fn transport(&self) -> Transport{
+        self.transport.unwrap_or(if self.cid.is_some() && self.host.is_none() { Transport::Vsock }else{ Transport::Ws })
+    }
+
+    /// the target as given on the command line, maybe a list of them
+    pub fn target(&self) -> std::result::Result<String,Box<dyn std::error::Error>>{
+        Ok(match self.transport(){
+            Transport::Vsock => self.cid.clone().ok_or("--cid is required")?,
+            Transport::Unix => self.host.clone().ok_or("--host is required, the path of the socket")?,
+            Transport::Stdio => self.host.clone().ok_or("--host is required, the command to run")?,
+            Transport::Tcp => self.host.clone().unwrap_or_else(|| DEFAULT_SSH_HOST.to_string()),
+            Transport::Ws | Transport::Wss => self.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_string()),
+        })
+    }
+
+    /// the single target to open an ssh session to
+    pub fn endpoint(&self) -> std::result::Result<Endpoint,Box<dyn std::error::Error>>{
+        self.parse(&self.target()?)
+    }
+
+    /// one of the targets, see `Endpoint::parse`
+    pub fn parse(&self,spec: &str) -> std::result::Result<Endpoint,Box<dyn std::error::Error>>{
+        let mut endpoint = Endpoint::parse(self.transport(), spec)?;
+        endpoint.connect_timeout = Duration::from_secs(self.connect_timeout);
+        endpoint.family = if self.ipv4 { Some(Family::V4) }else if self.ipv6 { Some(Family::V6) }else{ None };
+        Ok(endpoint)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family{
+    V4,
+    V6,
+}
+
+/// a target of the ssh session or the exec api
+#[derive(Clone, Debug)]
+pub struct Endpoint{
+    pub transport: Transport,
+    /// host:port, socket path, cid[:port] or command
+    pub address: String,
+    /// tls on top of the transport, https and wss
+    pub tls: bool,
+    pub connect_timeout: Duration,
+    /// the only kind of ip address tried, any when `None`
+    pub family: Option<Family>,
+    /// the name the tls certificate is checked against, the host when `None`
+    pub server_name: Option<String>,
+}
+
+impl Endpoint{
+    /// `spec` reached over `transport`, unless it starts with a scheme: `ws://` for the agent over tcp,
+    /// `wss://` or `tls://` for it over tls, `vsock://cid[:port]`, `unix:/path`, and `ssh://` or `tcp://`
+    /// for ssh straight over tcp like --transport tcp. with `--transport stdio` the whole spec is the command, it is not looked into
+    pub fn parse(transport: Transport,spec: &str) -> std::result::Result<Self,Box<dyn std::error::Error>>{
+        let (transport, address) = if transport == Transport::Stdio{
+            (transport, spec)
+        }else if let Some((scheme, address)) = spec.split_once("://"){
+            (scheme_transport(scheme).ok_or_else(|| format!("unknown scheme \"{}://\"",scheme))?, address)
+        }else if let Some(path) = spec.strip_prefix("unix:"){
+            (Transport::Unix, path)
+        }else{
+            (transport, spec)
+        };
+        if address.is_empty(){
+            return Err(format!("no address in \"{}\"",spec).into());
+        }
+        if transport == Transport::Vsock{
+            parse_vsock(address)?;
+        }
+        if matches!(transport, Transport::Tcp | Transport::Ws | Transport::Wss){
+            check_host(address).map_err(|e| format!("bad host \"{}\": {}",address,e))?;
+        }
+        // plain ssh goes to sshd's port unless told otherwise
+        let address = if transport == Transport::Tcp { with_port(address, 22) }else{ address.to_string() };
+        Ok(Self{
+            transport,
+            address,
+            tls: transport == Transport::Wss,
+            connect_timeout: Duration::from_secs(CONNECT_TIMEOUT),
+            family: None,
+            server_name: None,
+        })
+    }
+
+    /// host and port for the Host header and urls, the agent does not look at it off tcp
+    pub fn authority(&self) -> String{
+        match self.transport{
+            Transport::Tcp | Transport::Ws | Transport::Wss => bracket_ipv6(&self.address),
+            Transport::Vsock => format!("127.0.0.1:{}",self.address.split_once(':').map(|(_, port)| port.to_string()).unwrap_or_else(|| VSOCK_PORT.to_string())),
+            Transport::Unix | Transport::Stdio => "localhost".to_string(),
+        }
+    }
+
+    /// base url of the exec api
+    pub fn http_url(&self) -> String{
+        format!("{}://{}",if self.tls { "https" }else{ "http" },self.authority())
+    }
+
+    /// url of a websocket on the agent
+    pub fn ws_url(&self,path: &str) -> String{
+        let authority = if self.transport == Transport::Ws && self.address == DEFAULT_HOST{
+            DEFAULT_WS_AUTHORITY.to_string()
+        }else{
+            self.authority()
+        };
+        format!("{}://{}{}",if self.tls { "wss" }else{ "ws" },authority,path)
+    }
+
+    /// name checked against the agent's certificate
+    fn domain(&self) -> String{
+        if let Some(server_name) = &self.server_name{
+            return server_name.clone();
+        }
+        let authority = self.authority();
+        let host = match authority.rsplit_once(':'){
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => authority.as_str(),
+        };
+        host.trim_start_matches('[').trim_end_matches(']').to_string()
+    }
+
+    /// open the transport, with tls on top when the endpoint asks for it
+    pub async fn connect(&self,tls: Option<&native_tls::TlsConnector>) -> std::io::Result<EndpointStream>{
+        let stream = tokio::time::timeout(self.connect_timeout, self.connect_transport()).await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{}: connect timed out",self.address)))??;
+        if !self.tls{
+            return Ok(stream);
+        }
+        let connector = match tls{
+            Some(connector) => connector.clone(),
+            None => native_tls::TlsConnector::new().map_err(std::io::Error::other)?,
+        };
+        let stream = tokio_native_tls::TlsConnector::from(connector).connect(&self.domain(), stream).await
+            .map_err(std::io::Error::other)?;
+        Ok(EndpointStream::new(stream))
+    }
+
+    async fn connect_transport(&self) -> std::io::Result<EndpointStream>{
+        info!("connect {:?} {}",self.transport,self.address);
+        Ok(match self.transport{
+            Transport::Tcp | Transport::Ws | Transport::Wss => EndpointStream::new(self.connect_tcp().await?),
+            Transport::Unix => EndpointStream::new(tokio::net::UnixStream::connect(&self.address).await?),
+            Transport::Stdio =>{
+                let mut child = tokio::process::Command::new("sh").arg("-c").arg(&self.address)
+                    .stdin(std::process::Stdio::piped())
+                    .stdout(std::process::Stdio::piped())
+                    .spawn()?;
+                // the command sees its stdin close and exits once the stream is dropped
+                let (stdout, stdin) = (child.stdout.take(), child.stdin.take());
+                EndpointStream::new(tokio::io::join(stdout.expect("piped"), stdin.expect("piped")))
+            },
+            #[cfg(feature = "vsock-support")]
+            Transport::Vsock =>{
+                let (cid, port) = parse_vsock(&self.address).map_err(|e| std::io::Error::other(e.to_string()))?;
+                EndpointStream::new(VsockStream::connect(cid, port).await?)
+            },
+            #[cfg(not(feature = "vsock-support"))]
+            Transport::Vsock => unreachable!("rejected when parsed"),
+        })
+    }
+
+    /// the port when the address has none, ssh for plain tcp, http or https for the agent
+    fn default_port(&self) -> u16{
+        match self.transport{
+            Transport::Tcp => 22,
+            Transport::Wss => 443,
+            _ => 80,
+        }
+    }
+
+    /// host and port of a tcp address, the name known_hosts files it under
+    pub fn host_port(&self) -> (String, u16){
+        let address = with_port(&self.address, self.default_port());
+        let (host, port) = address.rsplit_once(':').expect("with_port adds a port");
+        (host.trim_start_matches('[').trim_end_matches(']').to_string(), port.parse().expect("with_port adds a port"))
+    }
+
+    /// host and port to look up in known_hosts, `None` when the agent is in between and the key is the agent's own;
+    /// `alias` names the host instead, the only way to check a stdio command's target
+    pub fn known_host(&self, alias: Option<&str>) -> Result<Option<(String, u16)>, Box<dyn std::error::Error>>{
+        match (self.transport, alias){
+            (Transport::Tcp | Transport::Stdio, Some(alias)) => Ok(Some(Endpoint::parse(Transport::Tcp, alias)?.host_port())),
+            (Transport::Tcp, None) => Ok(Some(self.host_port())),
+            (Transport::Stdio, None) => Err("--transport stdio needs --host-key-alias to check the host key against known_hosts".into()),
+            _ => Ok(None),
+        }
+    }
+
+    /// every resolved address of the allowed family, alternating between ipv6 and ipv4
+    /// starting with whichever the resolver put first
+    async fn resolve(&self) -> std::io::Result<Vec<SocketAddr>>{
+        let address = with_port(&self.address, self.default_port());
+        let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = (vec![], vec![]);
+        let mut first_v6 = None;
+        for addr in tokio::net::lookup_host(address.as_str()).await?{
+            match self.family{
+                Some(Family::V4) if !addr.is_ipv4() => continue,
+                Some(Family::V6) if !addr.is_ipv6() => continue,
+                _ => {},
+            }
+            if *first_v6.get_or_insert(addr.is_ipv6()) == addr.is_ipv6(){
+                first.push(addr);
+            }else{
+                second.push(addr);
+            }
+        }
+        if first.is_empty(){
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{}: no {}address",address,match self.family{
+                Some(Family::V4) => "ipv4 ",
+                Some(Family::V6) => "ipv6 ",
+                None => "",
+            })));
+        }
+        let mut addrs = vec![];
+        let (mut first, mut second) = (first.into_iter(), second.into_iter());
+        loop{
+            match (first.next(), second.next()){
+                (None, None) => return Ok(addrs),
+                (a, b) => addrs.extend(a.into_iter().chain(b)),
+            }
+        }
+    }
+
+    /// happy eyeballs, the next address is tried when the last one failed or is slow to answer,
+    /// the first connection wins
+    async fn connect_tcp(&self) -> std::io::Result<TcpStream>{
+        let mut addrs = self.resolve().await?.into_iter().peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        loop{
+            if let Some(addr) = addrs.next(){
+                info!("connect {}",addr);
+                attempts.push(async move{ TcpStream::connect(addr).await.map_err(|e| (addr, e)) });
+            }
+            if attempts.is_empty(){
+                return Err(last_error.expect("at least one address was tried"));
+            }
+            tokio::select! {
+                Some(res) = attempts.next() =>{
+                    match res{
+                        Ok(stream) =>{
+                            stream.set_nodelay(true)?;
+                            return Ok(stream);
+                        },
+                        Err((addr, e)) =>{
+                            info!("connect {}: {:?}",addr,e);
+                            last_error = Some(std::io::Error::new(e.kind(), format!("{}: {}",addr,e)));
+                        },
+                    }
+                }
+                _ = tokio::time::sleep(ATTEMPT_DELAY), if addrs.peek().is_some() => {}
+            }
+        }
+    }
+}
+
+/// `address` with `port` appended when it has none
+fn with_port(address: &str,port: u16) -> String{
+    if address.parse::<SocketAddr>().is_ok(){
+        return address.to_string();
+    }
+    if let Ok(ip) = address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>(){
+        return SocketAddr::new(ip, port).to_string();
+    }
+    match address.rsplit_once(':'){
+        Some((_, p)) if p.parse::<u16>().is_ok() => address.to_string(),
+        _ => format!("{}:{}",address,port),
+    }
+}
+
+/// an ipv6 literal without a port in brackets, as urls want it
+fn bracket_ipv6(address: &str) -> String{
+    if address.parse::<Ipv6Addr>().is_ok(){
+        format!("[{}]",address)
+    }else{
+        address.to_string()
+    }
+}
+
+/// `host[:port]` that makes a url, with the port a number when there is one
+fn check_host(address: &str) -> std::result::Result<(),String>{
+    let authority = bracket_ipv6(address).parse::<http::uri::Authority>().map_err(|e| e.to_string())?;
+    if authority.as_str().contains('@'){
+        return Err("no user info allowed".into());
+    }
+    match authority.as_str()[authority.host().len()..].strip_prefix(':'){
+        Some(port) if port.parse::<u16>().is_err() => Err(format!("bad port \"{}\"",port)),
+        _ => Ok(()),
+    }
+}
+
+/// `cid[:port]`
+#[cfg(feature = "vsock-support")]
+fn parse_vsock(address: &str) -> std::result::Result<(u32, u32),Box<dyn std::error::Error>>{
+    let (cid, port) = match address.split_once(':'){
+        Some((cid, port)) => (cid, port.parse().map_err(|_| format!("bad vsock port \"{}\"",port))?),
+        None => (address, VSOCK_PORT),
+    };
+    Ok((cid.parse().map_err(|_| format!("bad cid \"{}\"",cid))?, port))
+}
+
+#[cfg(not(feature = "vsock-support"))]
+fn parse_vsock(_address: &str) -> std::result::Result<(u32, u32),Box<dyn std::error::Error>>{
+    Err("built without vsock support".into())
+}
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send{}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S{}
+
+/// a connected endpoint, whatever the transport
+pub struct EndpointStream(Box<dyn AsyncStream>);
+
+impl EndpointStream{
+    pub fn new<S: AsyncStream + 'static>(stream: S) -> Self{
+        Self(Box::new(stream))
+    }
+}
+
+impl AsyncRead for EndpointStream{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for EndpointStream{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>>{
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl Connection for EndpointStream{
+    fn connected(&self) -> Connected{
+        Connected::new()
+    }
+}
+
+/// hyper connector that reaches the endpoint whatever the request uri says
+#[derive(Clone)]
+pub struct EndpointConnector{
+    pub endpoint: Endpoint,
+    pub tls: Option<native_tls::TlsConnector>,
+}
+
+impl tower_service::Service<Uri> for EndpointConnector{
+    type Response = EndpointStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<EndpointStream>> + Send>>;
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, _uri: Uri) -> Self::Future{
+        let connector = self.clone();
+        Box::pin(async move{ connector.endpoint.connect(connector.tls.as_ref()).await })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parse(transport: Transport,spec: &str) -> (Transport, String, bool){
+        let endpoint = Endpoint::parse(transport, spec).unwrap();
+        (endpoint.transport, endpoint.address, endpoint.tls)
+    }
+
+    #[test]
+    fn parse_without_scheme_keeps_the_transport(){
+        assert_eq!(parse(Transport::Ws, "10.0.0.1:7777"), (Transport::Ws, "10.0.0.1:7777".into(), false));
+        assert_eq!(parse(Transport::Wss, "agent:443"), (Transport::Wss, "agent:443".into(), true));
+        assert_eq!(parse(Transport::Tcp, "host:22"), (Transport::Tcp, "host:22".into(), false));
+        assert_eq!(parse(Transport::Unix, "/run/agent.sock"), (Transport::Unix, "/run/agent.sock".into(), false));
+    }
+
+    #[test]
+    fn parse_schemes(){
+        assert_eq!(parse(Transport::Ws, "wss://agent:443"), (Transport::Wss, "agent:443".into(), true));
+        assert_eq!(parse(Transport::Tcp, "ws://agent:7777"), (Transport::Ws, "agent:7777".into(), false));
+        assert_eq!(parse(Transport::Ws, "ssh://host:22"), (Transport::Tcp, "host:22".into(), false));
+        assert_eq!(parse(Transport::Ws, "unix:/run/agent.sock"), (Transport::Unix, "/run/agent.sock".into(), false));
+        assert_eq!(parse(Transport::Ws, "unix:///run/agent.sock"), (Transport::Unix, "/run/agent.sock".into(), false));
+    }
+
+    #[test]
+    fn parse_fills_in_the_ssh_port(){
+        assert_eq!(parse(Transport::Tcp, "host"), (Transport::Tcp, "host:22".into(), false));
+        assert_eq!(parse(Transport::Ws, "ssh://10.0.0.1"), (Transport::Tcp, "10.0.0.1:22".into(), false));
+        assert_eq!(parse(Transport::Tcp, "::1"), (Transport::Tcp, "[::1]:22".into(), false));
+        assert_eq!(parse(Transport::Tcp, "host:2222"), (Transport::Tcp, "host:2222".into(), false));
+        // the agent keeps what was given, the default port depends on tls
+        assert_eq!(parse(Transport::Ws, "agent"), (Transport::Ws, "agent".into(), false));
+    }
+
+    #[test]
+    fn default_targets(){
+        let options = EndpointOptions{ transport: Some(Transport::Tcp), ..EndpointOptions::default() };
+        assert_eq!(options.target().unwrap(), "127.0.0.1:22");
+        assert_eq!(EndpointOptions::default().target().unwrap(), "127.0.0.1:7777");
+    }
+
+    #[test]
+    fn parse_schemes_agree_with_transport_names(){
+        assert_eq!(parse(Transport::Ws, "tcp://host:22"), (Transport::Tcp, "host:22".into(), false));
+        assert_eq!(parse(Transport::Ws, "tls://agent:443"), (Transport::Wss, "agent:443".into(), true));
+    }
+
+    #[test]
+    fn parse_stdio_takes_the_spec_as_is(){
+        let command = "curl -sN https://proxy/ssh";
+        assert_eq!(parse(Transport::Stdio, command), (Transport::Stdio, command.into(), false));
+        assert_eq!(parse(Transport::Stdio, "unix:thing"), (Transport::Stdio, "unix:thing".into(), false));
+    }
+
+    #[test]
+    fn parse_rejects(){
+        assert!(Endpoint::parse(Transport::Ws, "ftp://host").is_err());
+        assert!(Endpoint::parse(Transport::Ws, "ws://").is_err());
+        assert!(Endpoint::parse(Transport::Ws, "").is_err());
+        assert!(Endpoint::parse(Transport::Stdio, "").is_err());
+    }
+
+    #[cfg(not(feature = "vsock-support"))]
+    #[test]
+    fn parse_vsock_needs_the_feature(){
+        assert!(Endpoint::parse(Transport::Ws, "vsock://3").is_err());
+    }
+
+    #[cfg(feature = "vsock-support")]
+    #[test]
+    fn parse_vsock(){
+        assert_eq!(parse(Transport::Vsock, "3"), (Transport::Vsock, "3".into(), false));
+        assert_eq!(parse(Transport::Ws, "vsock://3:1027"), (Transport::Vsock, "3:1027".into(), false));
+        assert!(Endpoint::parse(Transport::Vsock, "x").is_err());
+    }
+
+    #[test]
+    fn parse_checks_the_host(){
+        for bad in ["a b", "host:abc", "host:99999", "host:", "user@host:22", "ho/st", "[::1"]{
+            assert!(Endpoint::parse(Transport::Ws, bad).is_err(), "{} was accepted",bad);
+            assert!(Endpoint::parse(Transport::Tcp, bad).is_err(), "{} was accepted",bad);
+        }
+        for good in ["host", "host:7777", "10.0.0.1:80", "::1", "[::1]", "[::1]:7777", "fe80::1"]{
+            assert!(Endpoint::parse(Transport::Ws, good).is_ok(), "{} was rejected",good);
+        }
+        // paths and commands are not hosts
+        assert!(Endpoint::parse(Transport::Unix, "/run/my agent.sock").is_ok());
+        assert!(Endpoint::parse(Transport::Stdio, "nc host 22").is_ok());
+    }
+
+    #[test]
+    fn urls_bracket_ipv6(){
+        assert_eq!(Endpoint::parse(Transport::Ws, "::1").unwrap().http_url(), "http://[::1]");
+        assert_eq!(Endpoint::parse(Transport::Ws, "[::1]:7777").unwrap().http_url(), "http://[::1]:7777");
+        assert_eq!(Endpoint::parse(Transport::Wss, "fe80::1").unwrap().ws_url("/ops/ssh"), "wss://[fe80::1]/ops/ssh");
+        assert_eq!(Endpoint::parse(Transport::Ws, "::1").unwrap().domain(), "::1");
+    }
+
+    #[test]
+    fn ws_url_of_the_default_agent(){
+        assert_eq!(Endpoint::parse(Transport::Ws, DEFAULT_HOST).unwrap().ws_url("/ops/ssh"), "ws://127.0.0.1:1077/ops/ssh");
+        assert_eq!(Endpoint::parse(Transport::Ws, "agent:7777").unwrap().ws_url("/ops/ssh"), "ws://agent:7777/ops/ssh");
+        assert_eq!(Endpoint::parse(Transport::Wss, "agent:443").unwrap().http_url(), "https://agent:443");
+    }
+
+    #[test]
+    fn with_port_adds_a_missing_port(){
+        assert_eq!(with_port("host", 22), "host:22");
+        assert_eq!(with_port("host:2222", 22), "host:2222");
+        assert_eq!(with_port("10.0.0.1", 80), "10.0.0.1:80");
+        assert_eq!(with_port("::1", 22), "[::1]:22");
+        assert_eq!(with_port("[::1]", 22), "[::1]:22");
+        assert_eq!(with_port("[::1]:2222", 22), "[::1]:2222");
+    }
+
+    #[test]
+    fn host_port_for_known_hosts(){
+        assert_eq!(Endpoint::parse(Transport::Tcp, "host").unwrap().host_port(), ("host".into(), 22));
+        assert_eq!(Endpoint::parse(Transport::Tcp, "host:2222").unwrap().host_port(), ("host".into(), 2222));
+        assert_eq!(Endpoint::parse(Transport::Tcp, "[::1]:2222").unwrap().host_port(), ("::1".into(), 2222));
+        assert_eq!(Endpoint::parse(Transport::Tcp, "::1").unwrap().host_port(), ("::1".into(), 22));
+    }
+
+    #[test]
+    fn known_host_for_each_transport(){
+        let known_host = |transport, spec, alias| Endpoint::parse(transport, spec).unwrap().known_host(alias).map_err(|e| e.to_string());
+        assert_eq!(known_host(Transport::Tcp, "host:2222", None), Ok(Some(("host".into(), 2222))));
+        assert_eq!(known_host(Transport::Tcp, "host:2222", Some("other")), Ok(Some(("other".into(), 22))));
+        assert_eq!(known_host(Transport::Stdio, "nc host 2222", Some("host:2222")), Ok(Some(("host".into(), 2222))));
+        assert!(known_host(Transport::Stdio, "nc host 2222", None).is_err());
+        assert!(known_host(Transport::Stdio, "nc host 2222", Some("a b")).is_err());
+        assert_eq!(known_host(Transport::Ws, "host", Some("other")), Ok(None));
+    }
+}
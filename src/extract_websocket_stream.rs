@@ -9,11 +9,47 @@ use tokio::io::ReadBuf;
 use log::{info,warn};
 use futures_util::StreamExt;
 use futures_util::Sink;
+use tokio_tungstenite::client_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use crate::auth::AuthOptions;
 pub struct ExtractWebsocketStream<S>{
     pub websocket: tokio_tungstenite::WebSocketStream<S>,
     pub byte_buffer: ByteBuffer
 }
 
+impl<S> ExtractWebsocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin
+    {
+    /// the websocket handshake at `url` with the agent's headers, offering the configured subprotocols
+    pub async fn handshake(url: String,stream: S,auth: &AuthOptions) -> std::result::Result<Self,Box<dyn std::error::Error>>{
+        let mut request = url.into_client_request()?;
+        let path = request.uri().path_and_query().map(|p| p.to_string()).unwrap_or_default();
+        for (name, value) in auth.headers("GET", &path, b""){
+            let name = http::HeaderName::from_bytes(name.as_bytes())?;
+            // `-H Host: ...` replaces the host taken from the url
+            if name == http::header::HOST{
+                request.headers_mut().insert(name, value.parse()?);
+            }else{
+                request.headers_mut().append(name, value.parse()?);
+            }
+        }
+        if !auth.subprotocol.is_empty(){
+            request.headers_mut().insert("sec-websocket-protocol", auth.subprotocol.join(", ").parse()?);
+        }
+        let (websocket, response) = client_async(request, stream).await?;
+        // tungstenite leaves this check to us
+        if let Some(protocol) = response.headers().get("sec-websocket-protocol"){
+            let protocol = protocol.to_str()?;
+            if !auth.subprotocol.iter().any(|p| p == protocol){
+                return Err(format!("{}: the agent chose subprotocol \"{}\" that was not offered",path,protocol).into());
+            }
+            info!("{}: subprotocol {}",path,protocol);
+        }
+        Ok(Self{ websocket, byte_buffer: ByteBuffer::new() })
+    }
+}
+
 impl<S> AsyncRead for ExtractWebsocketStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin
@@ -46,11 +82,11 @@ where
                     }
                     return Poll::Ready(Ok(()));
                 }
-                return Poll::Pending;
+                Poll::Pending
             }
             Some(Err(e)) =>{
                 warn!("read error:{:?}",e);
-                return Poll::Ready(Err(std::io::Error::last_os_error()));
+                Poll::Ready(Err(std::io::Error::last_os_error()))
             }   
             None =>{
                 info!("websocket closed");
@@ -74,11 +110,11 @@ where
                 match Pin::new(&mut self.websocket).start_send(msg){
                     Ok(()) =>{
                         let len = buf.len();
-                        return Poll::Ready(Ok(len));
+                        Poll::Ready(Ok(len))
                     }
                     Err(e) =>{
                         info!("send fail:{:?}",e);
-                        return Poll::Ready(Err(std::io::Error::last_os_error()))
+                        Poll::Ready(Err(std::io::Error::last_os_error()))
                     }
                 }
             }
@@ -0,0 +1,274 @@
+use clap::Subcommand;
+use russh::{Channel, client::Msg};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::{FileAttributes, FileType};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use log::info;
+
+use crate::sftp::{self, base_name, remote_path};
+use crate::transfer::{self, TransferOptions};
+
+#[derive(Subcommand, Debug)]
+pub enum FsCommands{
+    /// list a remote directory
+    Ls{
+        /// remote path
+        #[arg(default_value = ".")]
+        path: String,
+        /// show mode, size and mtime
+        #[arg(short, long)]
+        long: bool,
+        /// show files starting with `.`
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// show the attributes of a remote file
+    Stat{
+        /// remote paths
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// remove remote files
+    Rm{
+        /// remote paths
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// remove directories and their contents
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// create a remote directory
+    Mkdir{
+        /// remote path
+        path: String,
+        /// create missing parents, no error if it exists
+        #[arg(short, long)]
+        parents: bool,
+    },
+    /// rename a remote file
+    Mv{
+        /// remote source
+        from: String,
+        /// remote destination
+        to: String,
+    },
+    /// change the mode of remote files
+    Chmod{
+        /// octal mode
+        mode: String,
+        /// remote paths
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// print remote files to stdout
+    Cat{
+        /// remote paths
+        #[arg(required = true)]
+        paths: Vec<String>,
+        #[command(flatten)]
+        options: ReadOptions,
+    },
+}
+
+/// the transfer options that apply to reading a file
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReadOptions{
+    /// bytes per sftp read request
+    #[arg(long, default_value_t = transfer::CHUNK_SIZE)]
+    pub chunk_size: usize,
+    /// number of sftp file handles, each with one read request in flight
+    #[arg(long, default_value_t = transfer::CONCURRENCY)]
+    pub concurrency: usize,
+}
+
+impl ReadOptions{
+    fn transfer(&self,limit_rate: Option<u64>) -> TransferOptions{
+        TransferOptions{ chunk_size: self.chunk_size, concurrency: self.concurrency, verify: false, atomic: false, limit_rate }
+    }
+}
+
+/// what `ls` and `stat` report about a file
+#[derive(Serialize, Debug)]
+struct FileInfo{
+    name: String,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+    size: Option<u64>,
+    mode: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u32>,
+    link_target: Option<String>,
+}
+
+impl FileInfo{
+    async fn new(sftp: &SftpSession,name: String,path: &str,attrs: &FileAttributes) -> Self{
+        let file_type = match attrs.file_type(){
+            FileType::Dir => "dir",
+            FileType::File => "file",
+            FileType::Symlink => "symlink",
+            FileType::Other => "other",
+        };
+        let link_target = if attrs.file_type() == FileType::Symlink{
+            sftp.read_link(path).await.ok()
+        }else{
+            None
+        };
+        Self{
+            name,
+            file_type,
+            size: attrs.size,
+            mode: attrs.permissions.map(|p| format!("{:04o}",p & 0o7777)),
+            uid: attrs.uid,
+            gid: attrs.gid,
+            mtime: attrs.mtime,
+            link_target,
+        }
+    }
+
+    /// `ls -l` style line
+    fn long(&self) -> String{
+        let kind = match self.file_type{
+            "dir" => 'd',
+            "symlink" => 'l',
+            "file" => '-',
+            _ => '?',
+        };
+        let mode = self.mode.as_deref().and_then(|m| u32::from_str_radix(m, 8).ok()).unwrap_or(0);
+        let rwx: String = (0..9).rev().map(|bit| if mode & (1 << bit) == 0 { '-' }else{ ['x', 'w', 'r'][bit % 3] }).collect();
+        let mtime = self.mtime
+            .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let mut line = format!("{}{} {:>5} {:>5} {:>10} {} {}",kind,rwx,self.uid.unwrap_or(0),self.gid.unwrap_or(0),self.size.unwrap_or(0),mtime,self.name);
+        if let Some(target) = &self.link_target{
+            line.push_str(" -> ");
+            line.push_str(target);
+        }
+        line
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    println!("{}",serde_json::to_string(value)?);
+    Ok(())
+}
+
+pub async fn fs_loop(json: bool,command: FsCommands,limit_rate: Option<u64>,channel: Channel<Msg>) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    // cat reads with its own options, the other commands send one small request at a time
+    let options = match &command{
+        FsCommands::Cat { options, .. } => options.transfer(limit_rate),
+        _ => ReadOptions{ chunk_size: transfer::CHUNK_SIZE, concurrency: 1 }.transfer(limit_rate),
+    };
+    let sftp = sftp::open_session(channel, &options).await?;
+    match command{
+        FsCommands::Ls { path, long, all } =>{
+            let attrs = sftp.symlink_metadata(path.as_str()).await?;
+            let mut files = vec![];
+            if attrs.is_dir(){
+                let mut entries: Vec<_> = sftp.read_dir(path.as_str()).await?.filter(|e| all || !e.file_name().starts_with('.')).collect();
+                entries.sort_by_key(|e| e.file_name());
+                for entry in entries{
+                    let entry_path = remote_path(&path, &entry.file_name());
+                    files.push(FileInfo::new(&sftp, entry.file_name(), &entry_path, &entry.metadata()).await);
+                }
+            }else{
+                files.push(FileInfo::new(&sftp, path.clone(), &path, &attrs).await);
+            }
+            if json{
+                print_json(&files)?;
+            }else{
+                for file in files{
+                    println!("{}",if long { file.long() }else{ file.name });
+                }
+            }
+        },
+        FsCommands::Stat { paths } =>{
+            let mut files = vec![];
+            for path in &paths{
+                let attrs = sftp.symlink_metadata(path.as_str()).await?;
+                files.push(FileInfo::new(&sftp, base_name(path).to_string(), path, &attrs).await);
+            }
+            if json{
+                print_json(&files)?;
+            }else{
+                for (path, file) in paths.iter().zip(files){
+                    println!("  File: {}{}",path,file.link_target.as_ref().map(|t| format!(" -> {}",t)).unwrap_or_default());
+                    println!("  Type: {}",file.file_type);
+                    println!("  Size: {}",file.size.unwrap_or(0));
+                    println!("  Mode: {}",file.mode.unwrap_or_default());
+                    println!("   Uid: {}",file.uid.unwrap_or(0));
+                    println!("   Gid: {}",file.gid.unwrap_or(0));
+                    println!(" Mtime: {}",file.mtime.unwrap_or(0));
+                }
+            }
+        },
+        FsCommands::Rm { paths, recursive } =>{
+            for path in paths{
+                let attrs = sftp.symlink_metadata(path.as_str()).await?;
+                if !attrs.is_dir(){
+                    sftp.remove_file(path).await?;
+                }else if recursive{
+                    remove_tree(&sftp, &path).await?;
+                }else{
+                    return Err(format!("{}: is a directory",path).into());
+                }
+            }
+        },
+        FsCommands::Mkdir { path, parents } =>{
+            if parents{
+                let mut current = if path.starts_with('/'){ String::from("/") }else{ String::new() };
+                for component in path.split('/').filter(|c| !c.is_empty()){
+                    current = if current.is_empty(){ component.to_string() }else{ remote_path(&current, component) };
+                    if !sftp.try_exists(current.as_str()).await?{
+                        sftp.create_dir(current.as_str()).await?;
+                    }
+                }
+            }else{
+                sftp.create_dir(path).await?;
+            }
+        },
+        FsCommands::Mv { from, to } => sftp.rename(from, to).await?,
+        FsCommands::Chmod { mode, paths } =>{
+            let mode = u32::from_str_radix(&mode, 8).map_err(|_| format!("bad mode \"{}\"",mode))?;
+            for path in paths{
+                // FileAttributes::default() is not empty, only send the permissions
+                let attrs = FileAttributes{ permissions: Some(mode), ..FileAttributes::empty() };
+                sftp.set_metadata(path, attrs).await?;
+            }
+        },
+        FsCommands::Cat { paths, .. } =>{
+            let mut stdout = tokio::io::stdout();
+            for path in paths{
+                transfer::download(&sftp, &path, &mut stdout, &options).await?;
+            }
+            stdout.flush().await?;
+        },
+    }
+    Ok(())
+}
+
+/// remove a directory and everything below it, symlinks are removed, not followed
+async fn remove_tree(sftp: &SftpSession,path: &str) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let mut dirs = vec![path.to_string()];
+    let mut removed = vec![];
+    while let Some(dir) = dirs.pop(){
+        for entry in sftp.read_dir(dir.as_str()).await?{
+            let entry_path = remote_path(&dir, &entry.file_name());
+            if entry.file_type().is_dir(){
+                dirs.push(entry_path);
+            }else{
+                info!("remove {}",entry_path);
+                sftp.remove_file(entry_path).await?;
+            }
+        }
+        removed.push(dir);
+    }
+    // parents were pushed before their children
+    for dir in removed.iter().rev(){
+        info!("remove dir {}",dir);
+        sftp.remove_dir(dir.as_str()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use nix::fcntl::{flock, FlockArg};
+use serde::{Serialize, Deserialize};
+
+/// runs kept in the history file
+const KEEP: usize = 200;
+
+/// the file is cut back to `KEEP` runs once it holds this many
+const TRIM_AT: usize = KEEP * 2;
+
+/// one `exec run`, a json line in the history file
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct HistoryEntry{
+    pub command_id: String,
+    pub client_token: String,
+    pub target: String,
+    /// unix seconds
+    pub time: i64,
+    pub command: String,
+}
+
+fn path() -> std::result::Result<String,Box<dyn std::error::Error>>{
+    Ok(std::env::var("HOME")? + "/russh-ssh-client.history")
+}
+
+fn parse(text: &str) -> Vec<HistoryEntry>{
+    // a damaged line should not hide the rest
+    text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+}
+
+fn read_all() -> std::result::Result<Vec<HistoryEntry>,Box<dyn std::error::Error>>{
+    match std::fs::read_to_string(path()?){
+        Ok(text) => Ok(parse(&text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// record a run, dropping the oldest ones beyond `KEEP` now and then.
+/// the file is only readable by its owner, and an exclusive lock on it serializes
+/// the writers, the tasks of a fan-out as well as other processes
+pub fn append(entry: HistoryEntry) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let mut file = OpenOptions::new().read(true).append(true).create(true).mode(0o600).open(path()?)?;
+    // released when the file is closed
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+    // files from before the history was private
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(format!("{}\n",serde_json::to_string(&entry)?).as_bytes())?;
+    let mut text = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut text)?;
+    let entries = parse(&text);
+    if entries.len() >= TRIM_AT{
+        let mut kept = String::new();
+        for entry in &entries[entries.len() - KEEP..]{
+            kept.push_str(&serde_json::to_string(entry)?);
+            kept.push('\n');
+        }
+        // appends go to the end, which is the start again after this
+        file.set_len(0)?;
+        file.write_all(kept.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// the last `limit` runs, oldest first
+pub fn recent(limit: usize) -> std::result::Result<Vec<HistoryEntry>,Box<dyn std::error::Error>>{
+    let mut entries = read_all()?;
+    let skip = entries.len().saturating_sub(limit);
+    Ok(entries.split_off(skip))
+}
@@ -1,25 +1,42 @@
+mod attach;
+mod auth;
 mod command;
+mod endpoint;
+mod history;
 mod ssh;
 mod extract_websocket_stream;
 mod async_fs_stream;
+mod sftp;
+mod transfer;
+mod channel_stream;
+mod checksum;
+mod raw_sftp;
+mod sync;
+mod fs;
+mod rate_limit;
+mod progress;
+mod copy;
+mod printer;
 use std::sync::Arc;
 use async_trait::async_trait;
-use command::{command_loop, ExecCommands};
+use auth::AuthOptions;
+use command::{exec_loop, ExecCommands, HttpOptions, OutputFormat};
+use endpoint::{Endpoint, EndpointOptions, EndpointStream, Transport};
 use env_logger::Target;
 use russh::*;
 use russh_keys::*;
-use tokio_tungstenite::{connect_async, client_async};
-use bytebuffer::ByteBuffer;
 use log::info;
 use std::io::Write;
-#[cfg(feature = "vsock-support")]
-use tokio_vsock::VsockStream;
+use transfer::TransferOptions;
 
 use crossterm::terminal::disable_raw_mode;
 use crate::extract_websocket_stream::ExtractWebsocketStream;
 use clap::{Parser,Subcommand};
 
-struct Client {}
+struct Client {
+    /// host and port checked against `~/.ssh/known_hosts`, `None` when the agent is in between
+    known_host: Option<(String, u16)>,
+}
 
 
 #[async_trait]
@@ -28,29 +45,84 @@ impl client::Handler for Client {
 
     async fn check_server_key(
         self,
-        _: &key::PublicKey,
+        server_public_key: &key::PublicKey,
     ) -> std::result::Result<(Self, bool), Self::Error> {
-        Ok((self, true))
+        let Some((host, port)) = &self.known_host else{
+            return Ok((self, true));
+        };
+        let Ok(home_dir) = std::env::var("HOME") else{
+            eprintln!("HOME is not set, cannot check the host key of {}",host);
+            return Ok((self, false));
+        };
+        let known_hosts = home_dir + "/.ssh/known_hosts";
+        let accept = match check_known_hosts_path(host, *port, server_public_key, &known_hosts){
+            Ok(true) => true,
+            Ok(false) =>{
+                match learn_known_hosts_path(host, *port, server_public_key, &known_hosts){
+                    Ok(()) => eprintln!("Permanently added '{}' ({} {}) to {}", host, server_public_key.name(), server_public_key.fingerprint(), known_hosts),
+                    Err(e) => eprintln!("Failed to add '{}' to {}: {}", host, known_hosts, e),
+                }
+                true
+            },
+            Err(e) =>{
+                eprintln!("host key verification failed for '{}' ({} {}), {}: {}", host, server_public_key.name(), server_public_key.fingerprint(), known_hosts, e);
+                false
+            },
+        };
+        info!("host key of {}:{} accepted:{}",host,port,accept);
+        Ok((self, accept))
     }
 }
 
+/// who to log in as on the target
+#[derive(clap::Args, Debug)]
+struct LoginOptions{
+    /// user to log in as
+    #[arg(long, default_value_t = String::from("zhuming"))]
+    user: String,
+    /// private key to log in with, `$HOME/.ssh/id_ed25519` when not given
+    #[arg(short = 'i', long)]
+    identity: Option<std::path::PathBuf>,
+    /// host[:port] to check the host key against in known_hosts, required with --transport stdio
+    #[arg(long)]
+    host_key_alias: Option<String>,
+}
+
 
 /// ssh args
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[cfg(feature="vsock-support")]
-    /// cid
-    #[arg(short, long)]
-    cid: Option<String>,
+    #[command(flatten)]
+    endpoint: EndpointOptions,
+
+    #[command(flatten)]
+    auth: AuthOptions,
+
+    #[command(flatten)]
+    login: LoginOptions,
+
+    /// limit the session to this many bytes per second each way, k, m and g suffixes allowed
+    #[arg(long, global = true, value_parser = rate_limit::parse_rate)]
+    limit_rate: Option<u64>,
 
     #[command(subcommand)]
     command: Option<Commands>,
 }
+// parsed once, the size of the exec options does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands{
     /// exec command
     Exec{
+        /// how responses are printed
+        #[arg(long, value_enum, default_value_t = OutputFormat::Raw, global = true)]
+        output: OutputFormat,
+        /// targets to run on at the same time
+        #[arg(long, default_value_t = 16, global = true)]
+        parallel: usize,
+        #[command(flatten)]
+        http: HttpOptions,
         #[command(subcommand)]
         command: ExecCommands,
     },
@@ -65,16 +137,116 @@ enum Commands{
         /// if from remote to local
         #[arg(short, long, default_value_t = false)]
         reverse: bool,
-        /// remote location
-        #[arg(short, long)]
-        remote: String,
+        /// remote location, a glob downloads all matches into the local directory
+        #[arg(short = 'R', long, required_unless_present = "batch")]
+        remote: Option<String>,
+
+        /// local location, `-` for stdin or stdout, a glob uploads all matches into the remote directory
+        #[arg(short, long, required_unless_present = "batch")]
+        local: Option<String>,
+
+        /// batch file of sftp commands, `-` for stdin
+        #[arg(short, long, conflicts_with_all = ["remote", "local"])]
+        batch: Option<String>,
+
+        #[command(flatten)]
+        options: TransferOptions,
+    },
+    /// remote file operations over sftp
+    Fs{
+        /// print json instead of text
+        #[arg(long, global = true, default_value_t = false)]
+        json: bool,
+
+        #[command(subcommand)]
+        command: fs::FsCommands,
+    },
+    /// copy files from this target to another one, without touching local disk
+    Copy{
+        /// source path on this target
+        from: String,
+        /// destination path on the other target
+        to: String,
+
+        /// how to reach the destination target, vsock when only --to-cid is given, ws otherwise. not taken from --transport
+        #[arg(long, value_enum)]
+        to_transport: Option<Transport>,
+
+        /// host:port, socket path or command of the destination target
+        #[arg(long, required_unless_present = "to_cid")]
+        to_host: Option<String>,
 
-        /// local location
-        #[arg(short, long)]
-        local: String,
+        /// cid of the destination target
+        #[arg(long, conflicts_with = "to_host")]
+        to_cid: Option<String>,
+
+        /// copy directories recursively
+        #[arg(short, long, default_value_t = false)]
+        recursive: bool,
+
+        #[command(flatten)]
+        options: TransferOptions,
+    },
+    /// sync a local and a remote directory over sftp
+    Sync{
+        #[command(flatten)]
+        sync: sync::SyncOptions,
+
+        #[command(flatten)]
+        options: TransferOptions,
     }
 }
 
+/// open an authenticated ssh session to a target
+async fn connect(
+    mut endpoint: Endpoint,
+    auth: &AuthOptions,
+    login: &LoginOptions,
+    limit_rate: Option<u64>) -> std::result::Result<client::Handle<Client>,Box<dyn std::error::Error>>{
+    let config = client::Config {
+        inactivity_timeout: Some(std::time::Duration::from_secs(5)),
+        ..<_>::default()
+    };
+    let config = Arc::new(config);
+    let known_host = endpoint.known_host(login.host_key_alias.as_deref())?;
+    let sh = Client { known_host };
+    let identity = match &login.identity{
+        Some(identity) => identity.clone(),
+        None => (std::env::var("HOME")? + "/.ssh/id_ed25519").into(),
+    };
+    let key_pair = load_secret_key(&identity, None).map_err(|e| format!("{}: {}",identity.display(),e))?;
+
+
+    // websocket
+    //------------------------------------------------------------------------------------------------------------------------
+    // let key_pair = load_secret_key("~/.ssh/id_ed25519", None)?;
+    // let url = url::Url::parse("ws://127.0.0.1:7777/ops/ssh").unwrap();
+    // let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
+    // let wsss = WebsocketStream{websocket:ws_stream,byte_buffer:ByteBuffer::new()};
+    //------------------------------------------------------------------------------------------------------------------------
+
+    //websocket - over tcp, tls, unix socket or vsock, or ssh straight over tcp or stdio
+    //------------------------------------------------------------------------------------------------------------------------
+    auth.apply(&mut endpoint)?;
+    let wsss = endpoint.connect(auth.connector(&endpoint)?.as_ref()).await?;
+    let wsss = if endpoint.transport.websocket(){
+        EndpointStream::new(ExtractWebsocketStream::handshake(endpoint.ws_url("/ops/ssh"), wsss, auth).await?)
+    }else{
+        wsss
+    };
+    //------------------------------------------------------------------------------------------------------------------------
+
+    let wsss = rate_limit::RateLimitedStream::new(wsss, limit_rate);
+    let mut session = russh::client::connect_stream(config, wsss, sh).await?;
+    let auth_res = session
+    .authenticate_publickey(login.user.as_str(), Arc::new(key_pair))
+    .await?;
+    if !auth_res{
+        return Err(format!("{}: the target refused the key {}",login.user,identity.display()).into());
+    }
+    Ok(session)
+}
+
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn std::error::Error>>{
     let mut builder = env_logger::Builder::from_default_env();
@@ -92,7 +264,7 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>>{
         )
     });
     let home_dir =  std::env::var("HOME").unwrap();
-    let target = Box::new(std::fs::File::create(home_dir.clone() + "/russh-ssh-client.log").expect("Can't create file"));
+    let target = Box::new(std::fs::File::create(home_dir + "/russh-ssh-client.log").expect("Can't create file"));
     builder
     .filter_level(log::LevelFilter::Info)
     .target(Target::Pipe(target))
@@ -100,74 +272,39 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>>{
 
 
 
-    let config = client::Config {
-        inactivity_timeout: Some(std::time::Duration::from_secs(5)),
-        ..<_>::default()
-    };
-    let config = Arc::new(config);
-    let sh = Client {};
-    let key_pair = load_secret_key(home_dir + "/.ssh/id_ed25519", None)?;
     let args = Args::parse();
-
-
-    // websocket
-    //------------------------------------------------------------------------------------------------------------------------
-    // let key_pair = load_secret_key("~/.ssh/id_ed25519", None)?;
-    // let url = url::Url::parse("ws://127.0.0.1:7777/ops/ssh").unwrap();
-    // let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
-    // let wsss = WebsocketStream{websocket:ws_stream,byte_buffer:ByteBuffer::new()};
-    //------------------------------------------------------------------------------------------------------------------------
-
-    //websocket - over tcp
-    //------------------------------------------------------------------------------------------------------------------------
-    #[cfg(not(feature="vsock-support"))]
-    let wsss = {
-        let tcp_socket = tokio::net::TcpSocket::new_v4().unwrap();
-        let tcp_stream = tcp_socket.connect("127.0.0.1:7777".parse().unwrap()).await?;
-        let request = "ws://127.0.0.1:1077/ops/ssh";
-        let (ws_stream,_) = client_async(request, tcp_stream).await?;
-        ExtractWebsocketStream{ websocket: ws_stream, byte_buffer: ByteBuffer::new() }
-    };
-    //------------------------------------------------------------------------------------------------------------------------
-
-    //tcp
-    //------------------------------------------------------------------------------------------------------------------------
-    // #[cfg(not(feature="vsock-support"))]
-    // let wsss = tokio::net::TcpStream::connect(("127.0.0.1", 22)).await.unwrap();
-
-    //------------------------------------------------------------------------------------------------------------------------
-
-    // websocket - over vsock
-    //------------------------------------------------------------------------------------------------------------------------
-    #[cfg(feature="vsock-support")]
-    let wsss = {
-        let cid = u32::from_str_radix(args.cid.as_ref().unwrap(),10)?;
-        let vsock_stream = VsockStream::connect(cid, 1027).await?;
-        let request = "ws://127.0.0.1:1027/ops/ssh";
-        let (ws_stream,_) = client_async(request, vsock_stream).await?;
-        ExtractWebsocketStream{ websocket: ws_stream, byte_buffer: ByteBuffer::new() }
+    let auth = args.auth.with_config()?;
+    // exec talks to the agent over http, it needs no ssh session
+    let sub_cmd = match args.command{
+        Some(Commands::Exec { output, parallel, http, command }) =>{
+            let res = exec_loop(
+                &args.endpoint,
+                parallel,
+                http,
+                auth.clone(),
+                output,
+                command
+            ).await;
+            match res{
+                Ok(Some(code)) => std::process::exit(code),
+                Ok(None) => {},
+                Err(e) =>{
+                    info!("exec command error:{:?}",e);
+                    eprintln!("exec: {}",e);
+                    std::process::exit(1);
+                },
+            }
+            return Ok(());
+        },
+        other => other,
     };
-    //------------------------------------------------------------------------------------------------------------------------
-
-    let mut session = russh::client::connect_stream(config, wsss, sh).await?;
-    let _auth_res = session
-    .authenticate_publickey("zhuming", Arc::new(key_pair))
-    .await?;
+    let session = connect(args.endpoint.endpoint()?, &auth, &args.login, args.limit_rate).await?;
     let channel = session.channel_open_session().await?;
 
 
-    if let Some(sub_cmd) = args.command{
+    if let Some(sub_cmd) = sub_cmd{
         match sub_cmd{
-            Commands::Exec { command } =>{
-                let res = command_loop(
-                    #[cfg(feature="vsock-support")]
-                    args.cid,
-                    command
-                ).await;
-                if res.is_err(){
-                    info!("exec command error:{:?}",res.err())
-                }
-            },
+            Commands::Exec { .. } => unreachable!("exec runs before connecting"),
             Commands::Ssh { term } =>{
                 //异常的情况下，我们要额外进行一次disable raw mode
                 let ex = ssh::ssh_loop(term.as_str(), channel).await.or_else(|e| {
@@ -176,48 +313,49 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>>{
                 });
                 info!("ex:{:?}",ex);
             },
-            Commands::Sftp { reverse, remote, local } =>{
-                let res = sftp_loop(reverse, remote, local, channel).await;
+            Commands::Sftp { reverse, remote, local, batch, options } =>{
+                let options = TransferOptions{ limit_rate: args.limit_rate, ..options };
+                let res = match batch{
+                    Some(batch) => sftp::batch_loop(batch, options, &session, channel).await,
+                    None => sftp::sftp_loop(reverse, remote.unwrap(), local.unwrap(), options, &session, channel).await,
+                };
                 info!("sftp res:{:?}",res);
+                if let Err(e) = res{
+                    eprintln!("sftp: {}",e);
+                    std::process::exit(1);
+                }
+            },
+            Commands::Fs { json, command } =>{
+                let res = fs::fs_loop(json, command, args.limit_rate, channel).await;
+                info!("fs res:{:?}",res);
+                if let Err(e) = res{
+                    eprintln!("fs: {}",e);
+                    std::process::exit(1);
+                }
+            },
+            Commands::Copy { from, to, to_transport, to_host, to_cid, recursive, options } =>{
+                let options = TransferOptions{ limit_rate: args.limit_rate, ..options };
+                let res = async{
+                    let dest = EndpointOptions{ transport: to_transport, host: to_host, cid: to_cid, ..args.endpoint.clone() }.endpoint()?;
+                    let dest = connect(dest, &auth, &args.login, args.limit_rate).await?;
+                    copy::copy_loop(from, to, recursive, options, &session, channel, &dest).await
+                }.await;
+                info!("copy res:{:?}",res);
+                if let Err(e) = res{
+                    eprintln!("copy: {}",e);
+                    std::process::exit(1);
+                }
+            },
+            Commands::Sync { sync, options } =>{
+                let options = TransferOptions{ limit_rate: args.limit_rate, ..options };
+                let res = sync::sync_loop(sync, options, &session, channel).await;
+                info!("sync res:{:?}",res);
+                if let Err(e) = res{
+                    eprintln!("sync: {}",e);
+                    std::process::exit(1);
+                }
             }
         }
     }
     Ok(())
 }
-use russh_sftp::client::SftpSession;
-use russh::client::Msg;
-pub async fn sftp_loop(reverse: bool,remote: String,local: String,mut channel: Channel<Msg>) -> std::result::Result<(),Box<dyn std::error::Error>>{
-    if reverse{
-        //从远端到近端
-        info!("request");
-        channel.request_subsystem(true, "sftp").await?;
-        info!("session");
-        let sftp = SftpSession::new(channel.into_stream()).await?;
-        info!("remote file");
-        let mut remote_file = sftp.open(remote).await?;
-        info!("local file");
-        let mut local_file = tokio::fs::OpenOptions::new().create(true).write(true).open(local).await?;
-        info!("start to copy");
-        tokio::io::copy(&mut remote_file,&mut local_file).await?;
-        info!("copy finish");
-    }else{
-        // read write append truncate create create_new
-        //从近端到远端
-        info!("request");
-        channel.request_subsystem(true, "sftp").await?;
-        info!("session");
-        let sftp = SftpSession::new(channel.into_stream()).await?;
-        info!("local file");
-        let mut local_file = tokio::fs::OpenOptions::new().read(true).open(local).await?;
-        info!("remote file");
-        let mut remote_file = sftp.create(remote).await?;
-        info!("start to copy");
-        tokio::io::copy(&mut local_file,&mut remote_file).await?;
-        info!("copy finish");
-    }
-    Ok(())
-}
-
-
-
-
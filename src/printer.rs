@@ -0,0 +1,53 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+/// writes one target's results to stdout, with every line prefixed by the target
+/// when several targets print at once
+pub struct Printer{
+    prefix: Option<String>,
+    /// the unfinished last line, held back so lines of different targets do not mix
+    partial: Mutex<Vec<u8>>,
+}
+
+impl Printer{
+    pub fn new(target: Option<&str>) -> Self{
+        Self{ prefix: target.map(|t| format!("[{}] ",t)), partial: Mutex::new(vec![]) }
+    }
+
+    pub fn write(&self,data: &[u8]) -> std::io::Result<()>{
+        let Some(prefix) = &self.prefix else{
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(data)?;
+            return stdout.flush();
+        };
+        let mut partial = self.partial.lock().unwrap();
+        partial.extend_from_slice(data);
+        let Some(end) = partial.iter().rposition(|b| *b == b'\n') else{
+            return Ok(());
+        };
+        let lines: Vec<u8> = partial.drain(..=end).collect();
+        let mut stdout = std::io::stdout().lock();
+        for line in lines.split_inclusive(|b| *b == b'\n'){
+            stdout.write_all(prefix.as_bytes())?;
+            stdout.write_all(line)?;
+        }
+        stdout.flush()
+    }
+
+    pub fn line(&self,line: &str) -> std::io::Result<()>{
+        self.write(format!("{}\n",line).as_bytes())
+    }
+
+    /// a message on stderr
+    pub fn error(&self,message: &str){
+        eprintln!("{}{}",self.prefix.as_deref().unwrap_or_default(),message);
+    }
+
+    /// print what is left of an unfinished last line
+    pub fn finish(&self) -> std::io::Result<()>{
+        if self.partial.lock().unwrap().is_empty(){
+            return Ok(());
+        }
+        self.write(b"\n")
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::IsTerminal;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures::ready;
+use tokio::io::{AsyncRead, ReadBuf};
+
+fn human(bytes: f64) -> String{
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1{
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}",value,units[unit])
+}
+
+/// reports on stderr how much of `total` bytes were read through it,
+/// redrawn in place on a terminal and printed once at the end otherwise
+pub struct ProgressReader<R>{
+    inner: R,
+    name: String,
+    total: u64,
+    done: u64,
+    start: Instant,
+    last: Instant,
+    terminal: bool,
+    finished: bool,
+}
+
+impl<R> ProgressReader<R>{
+    pub fn new(inner: R,name: String,total: u64) -> Self{
+        let now = Instant::now();
+        Self{ inner, name, total, done: 0, start: now, last: now, terminal: std::io::stderr().is_terminal(), finished: false }
+    }
+
+    fn report(&self){
+        let secs = self.start.elapsed().as_secs_f64().max(0.001);
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100).min(100);
+        let line = format!("{} {:>3}% {} {}/s",self.name,percent,human(self.done as f64),human(self.done as f64 / secs));
+        if self.terminal{
+            eprint!("\r\x1b[K{}",line);
+            if self.finished{
+                eprintln!();
+            }
+        }else if self.finished{
+            eprintln!("{}",line);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R>{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>>{
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - filled;
+        self.done += n as u64;
+        if n == 0 && buf.remaining() > 0 && !self.finished{
+            self.finished = true;
+            self.report();
+        }else if self.last.elapsed() >= Duration::from_millis(200){
+            self.last = Instant::now();
+            self.report();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
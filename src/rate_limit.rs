@@ -0,0 +1,170 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// parse a rate in bytes per second, with an optional k, m or g suffix (powers of 1024)
+pub fn parse_rate(s: &str) -> std::result::Result<u64,String>{
+    let (number, unit) = match s.char_indices().last(){
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_lowercase()),
+        _ => (s, 'b'),
+    };
+    let shift = match unit{
+        'b' => 0,
+        'k' => 10,
+        'm' => 20,
+        'g' => 30,
+        _ => return Err(format!("unknown unit \"{}\"",unit)),
+    };
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(1 << shift)){
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid rate \"{}\"",s)),
+    }
+}
+
+/// token bucket, refilled at `rate` bytes per second up to `burst`
+struct Bucket{
+    rate: f64,
+    burst: f64,
+    /// tokens waited for before letting bytes through, a tenth of a second's worth at low rates
+    chunk: f64,
+    tokens: f64,
+    last: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bucket{
+    fn new(rate: u64) -> Self{
+        let burst = (rate as f64 / 4.0).max(4096.0);
+        let chunk = (rate / 10).clamp(1, 4096) as f64;
+        Self{ rate: rate as f64, burst, chunk, tokens: burst, last: Instant::now(), sleep: None }
+    }
+
+    /// bytes that may pass now, pending until enough tokens for a reasonable chunk
+    fn poll_available(&mut self,cx: &mut Context<'_>) -> Poll<usize>{
+        loop{
+            if let Some(sleep) = self.sleep.as_mut(){
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let now = Instant::now();
+            self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+            self.last = now;
+            if self.tokens >= self.chunk{
+                return Poll::Ready(self.tokens as usize);
+            }
+            let wait = Duration::from_secs_f64((self.chunk - self.tokens) / self.rate);
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    fn consume(&mut self,n: usize){
+        self.tokens -= n as f64;
+    }
+}
+
+/// limits reads and writes of the inner stream to `rate` bytes per second each way
+pub struct RateLimitedStream<S>{
+    inner: S,
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+}
+
+impl<S> RateLimitedStream<S>{
+    /// `None` passes everything through unlimited
+    pub fn new(inner: S,rate: Option<u64>) -> Self{
+        Self{ inner, read: rate.map(Bucket::new), write: rate.map(Bucket::new) }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimitedStream<S>{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>>{
+        let this = self.get_mut();
+        let Some(bucket) = this.read.as_mut() else{
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let available = ready!(bucket.poll_available(cx));
+        let mut limited = buf.take(available);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        // the inner stream filled part of `buf` through `limited`
+        unsafe{
+            buf.assume_init(n);
+        }
+        buf.advance(n);
+        bucket.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<S>{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>>{
+        let this = self.get_mut();
+        let Some(bucket) = this.write.as_mut() else{
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let available = ready!(bucket.poll_available(cx));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..buf.len().min(available)]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>>{
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parse_rate_units(){
+        assert_eq!(parse_rate("100"), Ok(100));
+        assert_eq!(parse_rate("100b"), Ok(100));
+        assert_eq!(parse_rate("2k"), Ok(2048));
+        assert_eq!(parse_rate("3M"), Ok(3 << 20));
+        assert_eq!(parse_rate("1g"), Ok(1 << 30));
+    }
+
+    #[test]
+    fn parse_rate_rejects_zero_and_garbage(){
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("0k").is_err());
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("k").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("10t").is_err());
+        assert!(parse_rate("1.5m").is_err());
+        assert!(parse_rate("10é").is_err());
+    }
+
+    #[tokio::test]
+    async fn low_rate_keeps_moving(){
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut remote, local) = tokio::io::duplex(65536);
+        remote.write_all(&[0; 8192]).await.unwrap();
+        let mut limited = RateLimitedStream::new(local, Some(300));
+        let mut buf = vec![0; 8192];
+        // the first read takes the initial burst, the next ones come a few bytes at a time
+        let mut total = 0;
+        for _ in 0..3{
+            let n = tokio::time::timeout(Duration::from_secs(1), limited.read(&mut buf)).await.expect("no progress within a second").unwrap();
+            assert!(n > 0);
+            total += n;
+        }
+        assert!((4096..4096 + 300).contains(&total), "read {}",total);
+    }
+
+    #[test]
+    fn parse_rate_rejects_overflow(){
+        assert_eq!(parse_rate(&u64::MAX.to_string()), Ok(u64::MAX));
+        assert!(parse_rate(&format!("{}k",u64::MAX)).is_err());
+        assert!(parse_rate("17179869184g").is_err());
+    }
+}
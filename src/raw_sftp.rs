@@ -0,0 +1,139 @@
+use russh::client;
+use russh_sftp::client::{RawSftpSession, SftpSession};
+use russh_sftp::protocol::{Packet, StatusCode, Version};
+use tokio::sync::OnceCell;
+
+use crate::{channel_stream, Client};
+
+pub const POSIX_RENAME: &str = "posix-rename@openssh.com";
+
+/// a sftp session on a channel of its own, for the server extensions
+/// and extended requests `SftpSession` does not expose
+pub async fn open(session: &client::Handle<Client>) -> std::result::Result<(RawSftpSession,Version),Box<dyn std::error::Error>>{
+    let mut channel = session.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await?;
+    let raw = RawSftpSession::new(channel_stream::into_stream(channel));
+    let version = raw.init().await?;
+    Ok((raw,version))
+}
+
+/// the ssh session under a sftp session, with a raw session for the extended
+/// requests that is opened on first use and shared by everything run over it
+pub struct Extended<'a>{
+    pub session: &'a client::Handle<Client>,
+    raw: OnceCell<(RawSftpSession,Version)>,
+}
+
+impl<'a> Extended<'a>{
+    pub fn new(session: &'a client::Handle<Client>) -> Self{
+        Extended{ session, raw: OnceCell::new() }
+    }
+
+    pub async fn raw(&self) -> std::result::Result<&RawSftpSession,Box<dyn std::error::Error>>{
+        Ok(&self.raw.get_or_try_init(|| open(self.session)).await?.0)
+    }
+
+    /// whether the server announced the extension `name`
+    pub async fn supports(&self,name: &str) -> std::result::Result<bool,Box<dyn std::error::Error>>{
+        self.raw().await?;
+        Ok(self.raw.get().map(|(_,version)| version.extensions.contains_key(name)).unwrap_or(false))
+    }
+}
+
+impl Drop for Extended<'_>{
+    fn drop(&mut self){
+        if let Some((raw,_)) = self.raw.get(){
+            let _ = raw.close_session();
+        }
+    }
+}
+
+pub fn put_string(data: &mut Vec<u8>,s: &[u8]){
+    data.extend((s.len() as u32).to_be_bytes());
+    data.extend(s);
+}
+
+/// rename that replaces an existing `newpath`, unlike the plain sftp rename
+pub async fn posix_rename(raw: &RawSftpSession,oldpath: &str,newpath: &str) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let mut data = vec![];
+    put_string(&mut data, oldpath.as_bytes());
+    put_string(&mut data, newpath.as_bytes());
+    match raw.extended(POSIX_RENAME, data).await?{
+        Packet::Status(status) if status.status_code == StatusCode::Ok => Ok(()),
+        Packet::Status(status) => Err(format!("{}: {}",POSIX_RENAME,status.error_message).into()),
+        _ => Err(format!("{}: unexpected reply",POSIX_RENAME).into()),
+    }
+}
+
+/// make `linkpath` a symbolic link to `target`. OpenSSH's sftp-server reads the SYMLINK
+/// arguments target first, the other way round from the draft russh-sftp follows,
+/// it is told apart by the extensions it announces
+pub async fn symlink(ext: &Extended<'_>,sftp: &SftpSession,linkpath: &str,target: &str) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let openssh = ext.supports(POSIX_RENAME).await?;
+    symlink_in_order(sftp, openssh, linkpath, target).await
+}
+
+async fn symlink_in_order(sftp: &SftpSession,openssh: bool,linkpath: &str,target: &str) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    if openssh{
+        sftp.symlink(target, linkpath).await?;
+    }else{
+        sftp.symlink(linkpath, target).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use russh_sftp::protocol::Status;
+    use super::*;
+
+    /// answers SYMLINK like OpenSSH's sftp-server, target first, or like the draft
+    struct LinkServer{
+        openssh: bool,
+    }
+
+    impl russh_sftp::server::Handler for LinkServer{
+        type Error = StatusCode;
+
+        fn unimplemented(&self) -> Self::Error{
+            StatusCode::OpUnsupported
+        }
+
+        async fn init(&mut self,_: u32,_: HashMap<String, String>) -> std::result::Result<Version,Self::Error>{
+            let mut version = Version::new();
+            if self.openssh{
+                version.extensions.insert(POSIX_RENAME.into(), "1".into());
+            }
+            Ok(version)
+        }
+
+        async fn symlink(&mut self,id: u32,first: String,second: String) -> std::result::Result<Status,Self::Error>{
+            let (target, linkpath) = if self.openssh { (first, second) }else{ (second, first) };
+            std::os::unix::fs::symlink(target, linkpath).map_err(|_| StatusCode::Failure)?;
+            Ok(Status{ id, status_code: StatusCode::Ok, error_message: "Ok".into(), language_tag: "en-US".into() })
+        }
+    }
+
+    async fn link_target(openssh: bool) -> std::path::PathBuf{
+        let (client, server) = tokio::io::duplex(65536);
+        russh_sftp::server::run(server, LinkServer{ openssh }).await;
+        let sftp = SftpSession::new(client).await.unwrap();
+        let linkpath = std::env::temp_dir().join(format!("raw-sftp-link-{}-{}",std::process::id(),openssh));
+        let _ = std::fs::remove_file(&linkpath);
+        symlink_in_order(&sftp, openssh, linkpath.to_str().unwrap(), "some/target").await.unwrap();
+        let target = std::fs::read_link(&linkpath).unwrap();
+        std::fs::remove_file(&linkpath).unwrap();
+        target
+    }
+
+    #[tokio::test]
+    async fn symlink_points_at_the_target_on_openssh(){
+        assert_eq!(link_target(true).await, std::path::Path::new("some/target"));
+    }
+
+    #[tokio::test]
+    async fn symlink_points_at_the_target_on_other_servers(){
+        assert_eq!(link_target(false).await, std::path::Path::new("some/target"));
+    }
+}
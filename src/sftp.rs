@@ -0,0 +1,448 @@
+use russh::{Channel, client, client::Msg};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::{FileAttributes, OpenFlags};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::info;
+
+use crate::{channel_stream, checksum, raw_sftp, Client};
+use crate::transfer::{self, TransferOptions};
+
+/// start the sftp subsystem on the channel opened in `main`.
+/// each file handle keeps one write in flight, so `--concurrency` handles bound the requests in flight
+pub async fn open_session(mut channel: Channel<Msg>,options: &TransferOptions) -> std::result::Result<SftpSession,Box<dyn std::error::Error>>{
+    info!("request");
+    channel.request_subsystem(true, "sftp").await?;
+    info!("session");
+    Ok(SftpSession::new_with_config(channel_stream::into_stream(channel), transfer::session_config(options)?).await?)
+}
+
+pub async fn sftp_loop(reverse: bool,remote: String,local: String,options: TransferOptions,session: &client::Handle<Client>,channel: Channel<Msg>) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let sftp = open_session(channel, &options).await?;
+    let ext = raw_sftp::Extended::new(session);
+    if reverse && has_glob(&remote){
+        //从远端到近端, 所有匹配的文件放到本地目录里
+        if !std::path::Path::new(&local).is_dir(){
+            return Err(format!("{}: not a directory",local).into());
+        }
+        let mut files = vec![];
+        for path in expand_remote(&sftp, &remote).await?{
+            if sftp.metadata(path.as_str()).await.map(|m| m.is_regular()).unwrap_or(false){
+                files.push(path);
+            }else{
+                info!("skip {}",path);
+            }
+        }
+        if files.is_empty(){
+            return Err(format!("{}: no matching files",remote).into());
+        }
+        for file in files{
+            let target = std::path::Path::new(&local).join(base_name(&file)).to_string_lossy().into_owned();
+            println!("Fetching {} to {}",file,target);
+            download(&ext, &sftp, &file, &target, &options).await?;
+        }
+    }else if reverse{
+        //从远端到近端
+        download(&ext, &sftp, &remote, &local, &options).await?;
+    }else if has_glob(&local){
+        //从近端到远端, 所有匹配的文件放到远端目录里
+        if !sftp.metadata(remote.as_str()).await.map(|m| m.is_dir()).unwrap_or(false){
+            return Err(format!("{}: not a directory",remote).into());
+        }
+        let mut files = vec![];
+        for path in glob::glob_with(&local, GLOB_OPTIONS)?{
+            let path = path?;
+            if path.is_file(){
+                files.push(path.to_string_lossy().into_owned());
+            }else{
+                info!("skip {}",path.display());
+            }
+        }
+        if files.is_empty(){
+            return Err(format!("{}: no matching files",local).into());
+        }
+        for file in files{
+            let target = remote_path(&remote, base_name(&file));
+            println!("Uploading {} to {}",file,target);
+            upload(&ext, &sftp, &file, &target, &options).await?;
+        }
+    }else{
+        //从近端到远端
+        upload(&ext, &sftp, &local, &remote, &options).await?;
+    }
+    Ok(())
+}
+
+/// like the shell, `*` and `?` do not match a leading `.`
+const GLOB_OPTIONS: glob::MatchOptions = glob::MatchOptions{ case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: true };
+
+fn has_glob(path: &str) -> bool{
+    path.contains(['*', '?', '['])
+}
+
+/// expand the glob in every component of a remote path against the directory listings
+async fn expand_remote(sftp: &SftpSession,path: &str) -> std::result::Result<Vec<String>,Box<dyn std::error::Error>>{
+    let (mut paths, rest) = match path.strip_prefix('/'){
+        Some(rest) => (vec![String::from("/")], rest),
+        None => (vec![String::new()], path),
+    };
+    for component in rest.split('/').filter(|c| !c.is_empty()){
+        let join = |dir: &str, name: &str| if dir.is_empty(){ name.to_string() }else{ remote_path(dir, name) };
+        if !has_glob(component){
+            paths = paths.iter().map(|dir| join(dir, component)).collect();
+            continue;
+        }
+        let pattern = glob::Pattern::new(component)?;
+        let mut matches = vec![];
+        for dir in &paths{
+            // not a directory, nothing below it can match
+            let Ok(entries) = sftp.read_dir(if dir.is_empty(){ "." }else{ dir.as_str() }).await else{
+                continue;
+            };
+            let mut names: Vec<_> = entries.map(|e| e.file_name()).filter(|name| pattern.matches_with(name, GLOB_OPTIONS)).collect();
+            names.sort();
+            matches.extend(names.iter().map(|name| join(dir, name)));
+        }
+        paths = matches;
+    }
+    Ok(paths)
+}
+
+/// copy `remote` to `local`, `-` for stdout
+pub async fn download(ext: &raw_sftp::Extended<'_>,sftp: &SftpSession,remote: &str,local: &str,options: &TransferOptions) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    info!("remote file:{}",remote);
+    info!("local file:{}",local);
+    let local_file: Box<dyn AsyncWrite + Unpin + Send> = if local == "-"{
+        Box::new(tokio::io::stdout())
+    }else{
+        Box::new(tokio::fs::OpenOptions::new().create(true).write(true).truncate(true).open(local).await?)
+    };
+    // hash what is written, so stdout can be verified as well
+    let mut writer = checksum::HashWriter::new(local_file, options.verify);
+    info!("start to copy");
+    transfer::download(sftp, remote, &mut writer, options).await?;
+    info!("copy finish");
+    if let Some(local_hash) = writer.finish(){
+        verify(ext, sftp, local, &local_hash, remote, options).await?;
+    }
+    Ok(())
+}
+
+/// copy `local`, `-` for stdin, to `remote`
+pub async fn upload(ext: &raw_sftp::Extended<'_>,sftp: &SftpSession,local: &str,remote: &str,options: &TransferOptions) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    info!("local file:{}",local);
+    let (local_file, len): (Box<dyn AsyncRead + Unpin + Send>, Option<u64>) = if local == "-"{
+        (Box::new(tokio::io::stdin()), None)
+    }else{
+        let file = tokio::fs::OpenOptions::new().read(true).open(local).await?;
+        let len = file.metadata().await?.len();
+        (Box::new(file), Some(len))
+    };
+    let mut reader = checksum::HashReader::new(local_file, options.verify);
+    info!("remote file:{}",remote);
+    info!("start to copy");
+    if options.atomic{
+        atomic_upload(ext, sftp, &mut reader, remote, len, options).await?;
+    }else{
+        transfer::upload(sftp, &mut reader, remote, len, options).await?;
+    }
+    info!("copy finish");
+    if let Some(local_hash) = reader.finish(){
+        verify(ext, sftp, local, &local_hash, remote, options).await?;
+    }
+    Ok(())
+}
+
+/// upload to a temporary sibling of `remote`, fsync it and rename it over `remote`,
+/// so readers see either the old or the new file but never a partial one
+pub async fn atomic_upload<R>(ext: &raw_sftp::Extended<'_>,sftp: &SftpSession,reader: &mut R,remote: &str,len: Option<u64>,options: &TransferOptions) -> std::result::Result<(),Box<dyn std::error::Error>>
+where
+    R: AsyncRead + Unpin{
+    // the plain sftp rename fails when `remote` exists and removing it first is not atomic,
+    // so without posix-rename@openssh.com give up before sending anything
+    if !ext.supports(raw_sftp::POSIX_RENAME).await?{
+        return Err(format!("--atomic: the server does not support {}",raw_sftp::POSIX_RENAME).into());
+    }
+    let (dir, name) = match remote.rsplit_once('/'){
+        Some((dir, name)) => (format!("{}/",dir), name),
+        None => (String::new(), remote),
+    };
+    let tmp = format!("{}.{}.{:08x}.tmp",dir,name,rand::random::<u32>());
+    info!("atomic upload via {}",tmp);
+    let res: std::result::Result<(),Box<dyn std::error::Error>> = async{
+        transfer::upload(sftp, reader, &tmp, len, options).await?;
+        // fsync@openssh.com, skipped when the server does not support it
+        let mut file = sftp.open_with_flags(tmp.as_str(), OpenFlags::WRITE).await?;
+        file.sync_all().await?;
+        file.shutdown().await?;
+        // keep the mode of the file being replaced
+        if let Ok(metadata) = sftp.metadata(remote).await{
+            let attrs = FileAttributes{ permissions: metadata.permissions, ..FileAttributes::empty() };
+            sftp.set_metadata(tmp.as_str(), attrs).await?;
+        }
+        raw_sftp::posix_rename(ext.raw().await?, &tmp, remote).await
+    }.await;
+    if res.is_err(){
+        let _ = sftp.remove_file(tmp.as_str()).await;
+    }
+    res
+}
+
+/// compare the sha256 of the data sent or received with the one of the remote file
+async fn verify(ext: &raw_sftp::Extended<'_>,sftp: &SftpSession,local: &str,local_hash: &str,remote: &str,options: &TransferOptions) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let remote_hash = checksum::remote_sha256(ext, sftp, remote, options).await?;
+    info!("sha256 local:{} remote:{}",local_hash,remote_hash);
+    if local_hash != remote_hash{
+        return Err(format!("sha256 mismatch: {} is {}, {} is {}",local,local_hash,remote,remote_hash).into());
+    }
+    eprintln!("sha256 verified: {}  {}",remote_hash,remote);
+    Ok(())
+}
+
+/// run the sftp commands of a batch file (`-` for stdin) over one session, like `sftp -b`.
+/// a failing command aborts the batch unless the line is prefixed with `-`,
+/// and a line prefixed with `@` is not echoed.
+pub async fn batch_loop(batch: String,options: TransferOptions,session: &client::Handle<Client>,channel: Channel<Msg>) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let script = if batch == "-"{
+        let mut script = String::new();
+        tokio::io::stdin().read_to_string(&mut script).await?;
+        script
+    }else{
+        tokio::fs::read_to_string(&batch).await?
+    };
+    let sftp = open_session(channel, &options).await?;
+    let ext = raw_sftp::Extended::new(session);
+    let mut cwd = sftp.canonicalize(".").await?;
+    for (index, line) in script.lines().enumerate(){
+        let Some(BatchLine{ line, ignore_error, echo }) = parse_line(line) else{
+            continue;
+        };
+        if echo{
+            println!("sftp> {}",line);
+        }
+        info!("batch line {}:{}",index + 1,line);
+        let res = match split_args(line){
+            Ok(args) => batch_command(&ext, &sftp, &mut cwd, &args, &options).await,
+            Err(e) => Err(e),
+        };
+        match res{
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) =>{
+                eprintln!("{}",e);
+                if !ignore_error{
+                    return Err(format!("batch aborted at line {}: {}",index + 1,line).into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// a batch line without its `-` and `@` prefixes
+#[derive(Debug, PartialEq)]
+struct BatchLine<'a>{
+    line: &'a str,
+    /// `-`, carry on when the command fails
+    ignore_error: bool,
+    /// no `@`
+    echo: bool,
+}
+
+/// `None` for blank lines and comments
+fn parse_line(line: &str) -> Option<BatchLine<'_>>{
+    let mut line = line.trim();
+    let mut ignore_error = false;
+    let mut echo = true;
+    loop{
+        if let Some(rest) = line.strip_prefix('-'){
+            ignore_error = true;
+            line = rest.trim_start();
+        }else if let Some(rest) = line.strip_prefix('@'){
+            echo = false;
+            line = rest.trim_start();
+        }else{
+            break;
+        }
+    }
+    if line.is_empty() || line.starts_with('#'){
+        return None;
+    }
+    Some(BatchLine{ line, ignore_error, echo })
+}
+
+/// execute one batch command, returns false when the batch should stop
+async fn batch_command(ext: &raw_sftp::Extended<'_>,sftp: &SftpSession,cwd: &mut String,args: &[String],options: &TransferOptions) -> std::result::Result<bool,Box<dyn std::error::Error>>{
+    let arg = |i: usize| -> std::result::Result<&str,Box<dyn std::error::Error>>{
+        args.get(i).map(|a| a.as_str()).ok_or_else(|| format!("{}: missing argument",args[0]).into())
+    };
+    match args[0].as_str(){
+        "bye" | "exit" | "quit" => return Ok(false),
+        "cd" =>{
+            let path = sftp.canonicalize(remote_path(cwd, arg(1)?)).await?;
+            if !sftp.metadata(path.as_str()).await?.is_dir(){
+                return Err(format!("Can't change directory: \"{}\" is not a directory",path).into());
+            }
+            *cwd = path;
+        }
+        "lcd" => std::env::set_current_dir(arg(1)?)?,
+        "pwd" => println!("Remote working directory: {}",cwd),
+        "lpwd" => println!("Local working directory: {}",std::env::current_dir()?.display()),
+        "get" =>{
+            let remote = remote_path(cwd, arg(1)?);
+            let mut local = args.get(2).cloned().unwrap_or_else(|| base_name(&remote).to_string());
+            if std::path::Path::new(&local).is_dir(){
+                local = std::path::Path::new(&local).join(base_name(&remote)).to_string_lossy().into_owned();
+            }
+            println!("Fetching {} to {}",remote,local);
+            download(ext, sftp, &remote, &local, options).await?;
+        }
+        "put" =>{
+            let local = arg(1)?;
+            let mut remote = remote_path(cwd, args.get(2).map(|a| a.as_str()).unwrap_or_else(|| base_name(local)));
+            if sftp.metadata(remote.as_str()).await.map(|m| m.is_dir()).unwrap_or(false){
+                remote = remote_path(&remote, base_name(local));
+            }
+            println!("Uploading {} to {}",local,remote);
+            upload(ext, sftp, local, &remote, options).await?;
+        }
+        "ls" =>{
+            let long = args.get(1).map(|a| a == "-l").unwrap_or(false);
+            let path = remote_path(cwd, args.get(if long { 2 } else { 1 }).map(|a| a.as_str()).unwrap_or("."));
+            let mut entries: Vec<_> = sftp.read_dir(path).await?.collect();
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries{
+                if long{
+                    let metadata = entry.metadata();
+                    println!("{:o} {:>10} {}",metadata.permissions.unwrap_or(0) & 0o7777,metadata.size.unwrap_or(0),entry.file_name());
+                }else{
+                    println!("{}",entry.file_name());
+                }
+            }
+        }
+        "mkdir" => sftp.create_dir(remote_path(cwd, arg(1)?)).await?,
+        "rmdir" => sftp.remove_dir(remote_path(cwd, arg(1)?)).await?,
+        "rm" => sftp.remove_file(remote_path(cwd, arg(1)?)).await?,
+        "rename" => sftp.rename(remote_path(cwd, arg(1)?), remote_path(cwd, arg(2)?)).await?,
+        "ln" | "symlink" =>{
+            // `ln [-s] oldpath newpath` like sftp, a hard link unless -s, `symlink` is always symbolic
+            let symbolic = args[0] == "symlink" || arg(1)? == "-s";
+            let skip = if arg(1)? == "-s" { 1 } else { 0 };
+            let (oldpath, newpath) = (arg(1 + skip)?, remote_path(cwd, arg(2 + skip)?));
+            if symbolic{
+                raw_sftp::symlink(ext, sftp, &newpath, oldpath).await?;
+            }else if !sftp.hardlink(remote_path(cwd, oldpath), newpath).await?{
+                return Err("ln: the server does not support hardlink@openssh.com, use ln -s".into());
+            }
+        }
+        "chmod" =>{
+            let mode = u32::from_str_radix(arg(1)?, 8).map_err(|_| format!("chmod: bad mode \"{}\"",args[1]))?;
+            let path = remote_path(cwd, arg(2)?);
+            // FileAttributes::default() is not empty, only send the permissions
+            let attrs = FileAttributes{ permissions: Some(mode), ..FileAttributes::empty() };
+            sftp.set_metadata(path, attrs).await?;
+        }
+        other => return Err(format!("Invalid command \"{}\"",other).into()),
+    }
+    Ok(true)
+}
+
+/// resolve a remote path against the remote working directory
+pub fn remote_path(cwd: &str,path: &str) -> String{
+    if path.starts_with('/'){
+        path.to_string()
+    }else{
+        format!("{}/{}",cwd.trim_end_matches('/'),path)
+    }
+}
+
+pub fn base_name(path: &str) -> &str{
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+/// split a batch line into arguments, honouring quotes and backslash escapes
+fn split_args(line: &str) -> std::result::Result<Vec<String>,Box<dyn std::error::Error>>{
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next(){
+        match (quote, c){
+            (_, '\\') =>{
+                current.extend(chars.next());
+                in_arg = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') =>{
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() =>{
+                if in_arg{
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) =>{
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quote.is_some(){
+        return Err("Unterminated quote".into());
+    }
+    if in_arg{
+        args.push(current);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn split_args_plain_and_quoted(){
+        assert_eq!(split_args("put  a.txt   /tmp/b").unwrap(), ["put", "a.txt", "/tmp/b"]);
+        assert_eq!(split_args("put 'a b.txt' \"c d\"").unwrap(), ["put", "a b.txt", "c d"]);
+        assert_eq!(split_args("rm a\\ b").unwrap(), ["rm", "a b"]);
+        assert_eq!(split_args("get x'y z'w").unwrap(), ["get", "xy zw"]);
+        assert_eq!(split_args("mkdir ''").unwrap(), ["mkdir", ""]);
+        assert!(split_args("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_args_unterminated_quote(){
+        assert!(split_args("put 'a.txt").is_err());
+        assert!(split_args("put \"a.txt").is_err());
+    }
+
+    #[test]
+    fn parse_line_prefixes(){
+        assert_eq!(parse_line("  ls -l "), Some(BatchLine{ line: "ls -l", ignore_error: false, echo: true }));
+        assert_eq!(parse_line("-rm x"), Some(BatchLine{ line: "rm x", ignore_error: true, echo: true }));
+        assert_eq!(parse_line("@ pwd"), Some(BatchLine{ line: "pwd", ignore_error: false, echo: false }));
+        assert_eq!(parse_line("@-rm x"), Some(BatchLine{ line: "rm x", ignore_error: true, echo: false }));
+        assert_eq!(parse_line("-@ rm x"), Some(BatchLine{ line: "rm x", ignore_error: true, echo: false }));
+    }
+
+    #[test]
+    fn parse_line_skips_blank_and_comments(){
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("# a comment"), None);
+        assert_eq!(parse_line("-"), None);
+    }
+
+    #[test]
+    fn remote_path_and_base_name(){
+        assert_eq!(remote_path("/home/u", "a"), "/home/u/a");
+        assert_eq!(remote_path("/", "a"), "/a");
+        assert_eq!(remote_path("/home/u", "/etc"), "/etc");
+        assert_eq!(base_name("/a/b/c"), "c");
+        assert_eq!(base_name("/a/b/"), "b");
+        assert_eq!(base_name("c"), "c");
+    }
+}
@@ -33,7 +33,7 @@ pub async fn ssh_loop(term: &str,mut channel: Channel<Msg>,) -> std::result::Res
         )
     };
 
-    let mut signals = Signals::new(&[
+    let mut signals = Signals::new([
         SIGWINCH
     ])?;
     let handle = signals.handle();
@@ -65,7 +65,7 @@ pub async fn ssh_loop(term: &str,mut channel: Channel<Msg>,) -> std::result::Res
                         // tokio::io::stdout().flush().await?;
                         // stdout.write_all(&data.to_vec()).await?;
                         // stdout.flush().await?;
-                        raw.write_all(&data.to_vec()).await?;
+                        raw.write_all(data).await?;
                         raw.flush().await?;
                         
                     }
@@ -84,5 +84,5 @@ pub async fn ssh_loop(term: &str,mut channel: Channel<Msg>,) -> std::result::Res
     }   
     handle.close();
     disable_raw_mode()?;
-    return Ok(());
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use glob::Pattern;
+use russh::{Channel, client, client::Msg};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::FileAttributes;
+use log::info;
+
+use crate::{checksum, raw_sftp, sftp, Client};
+use crate::sftp::{base_name, remote_path};
+use crate::transfer::TransferOptions;
+
+/// which trees to sync and how they are compared
+#[derive(clap::Args, Debug, Clone)]
+pub struct SyncOptions{
+    /// if from remote to local
+    #[arg(short, long, default_value_t = false)]
+    pub reverse: bool,
+    /// remote directory
+    #[arg(short = 'R', long)]
+    pub remote: String,
+    /// local directory
+    #[arg(short, long)]
+    pub local: String,
+    /// compare files by sha256 instead of size and mtime
+    #[arg(short, long, default_value_t = false)]
+    pub checksum: bool,
+    /// delete files that are not on the sending side
+    #[arg(long, default_value_t = false)]
+    pub delete: bool,
+    /// only sync files matching this glob, can be repeated
+    #[arg(long)]
+    pub include: Vec<String>,
+    /// skip files and directories matching this glob, can be repeated
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    /// print what would be done without changing anything
+    #[arg(short = 'n', long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry{
+    is_dir: bool,
+    size: u64,
+    /// seconds since the epoch, negative before it
+    mtime: i64,
+}
+
+/// globs without a `/` match the file name, the others the path relative to the tree
+struct Filter{
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter{
+    fn new(sync: &SyncOptions) -> std::result::Result<Self,Box<dyn std::error::Error>>{
+        let parse = |globs: &[String]| globs.iter().map(|g| Pattern::new(g)).collect::<std::result::Result<Vec<_>,_>>();
+        Ok(Self{ include: parse(&sync.include)?, exclude: parse(&sync.exclude)? })
+    }
+    fn matches(pattern: &Pattern,rel: &str) -> bool{
+        if pattern.as_str().contains('/'){
+            pattern.matches(rel)
+        }else{
+            pattern.matches(base_name(rel))
+        }
+    }
+    fn excluded(&self,rel: &str) -> bool{
+        self.exclude.iter().any(|p| Self::matches(p, rel))
+    }
+    fn included(&self,rel: &str) -> bool{
+        self.include.is_empty() || self.include.iter().any(|p| Self::matches(p, rel))
+    }
+}
+
+fn to_unix(time: SystemTime) -> i64{
+    match time.duration_since(UNIX_EPOCH){
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn from_unix(secs: i64) -> SystemTime{
+    if secs >= 0{
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    }else{
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+/// the parent directories of `rel`, outermost first
+fn parents(rel: &str) -> impl Iterator<Item = &str>{
+    rel.match_indices('/').map(move |(i,_)| &rel[..i])
+}
+
+/// path of `rel` inside the tree at `root`
+/// `path` without its trailing slashes, except that `/` stays the root
+fn trim_root(path: &str) -> &str{
+    match path.trim_end_matches('/'){
+        "" if path.starts_with('/') => "/",
+        trimmed => trimmed,
+    }
+}
+
+fn join(root: &str,rel: &str) -> String{
+    if rel.is_empty(){
+        root.to_string()
+    }else if root.is_empty(){
+        rel.to_string()
+    }else{
+        remote_path(root, rel)
+    }
+}
+
+async fn local_tree(root: &str,filter: &Filter) -> std::result::Result<BTreeMap<String,Entry>,Box<dyn std::error::Error>>{
+    let mut tree = BTreeMap::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop(){
+        let mut read_dir = tokio::fs::read_dir(join(root, &dir)).await?;
+        while let Some(entry) = read_dir.next_entry().await?{
+            let rel = join(&dir, &entry.file_name().to_string_lossy());
+            if filter.excluded(&rel){
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir(){
+                dirs.push(rel.clone());
+                tree.insert(rel, Entry{ is_dir: true, size: 0, mtime: 0 });
+            }else if metadata.is_file(){
+                let mtime = to_unix(metadata.modified()?);
+                tree.insert(rel, Entry{ is_dir: false, size: metadata.len(), mtime });
+            }else{
+                info!("skip local {}",rel);
+            }
+        }
+    }
+    Ok(tree)
+}
+
+async fn remote_tree(sftp: &SftpSession,root: &str,filter: &Filter) -> std::result::Result<BTreeMap<String,Entry>,Box<dyn std::error::Error>>{
+    let mut tree = BTreeMap::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop(){
+        for entry in sftp.read_dir(join(root, &dir)).await?{
+            let rel = join(&dir, &entry.file_name());
+            if filter.excluded(&rel){
+                continue;
+            }
+            let metadata = entry.metadata();
+            if metadata.is_dir(){
+                dirs.push(rel.clone());
+                tree.insert(rel, Entry{ is_dir: true, size: 0, mtime: 0 });
+            }else if metadata.is_regular(){
+                tree.insert(rel, Entry{ is_dir: false, size: metadata.len(), mtime: metadata.mtime.unwrap_or(0) as i64 });
+            }else{
+                info!("skip remote {}",rel);
+            }
+        }
+    }
+    Ok(tree)
+}
+
+/// make the directory `rel` on the receiving side
+async fn make_dir(sync: &SyncOptions,sftp: &SftpSession,local_root: &str,remote_root: &str,rel: &str) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    println!("mkdir {}",rel);
+    if !sync.dry_run{
+        if sync.reverse{
+            tokio::fs::create_dir(join(local_root, rel)).await?;
+        }else{
+            sftp.create_dir(join(remote_root, rel)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// make the destination tree look like the source tree, transferring only the files
+/// whose size and mtime (or sha256 with `--checksum`) differ
+pub async fn sync_loop(sync: SyncOptions,options: TransferOptions,session: &client::Handle<Client>,channel: Channel<Msg>) -> std::result::Result<(),Box<dyn std::error::Error>>{
+    let filter = Filter::new(&sync)?;
+    let sftp = sftp::open_session(channel, &options).await?;
+    let ext = raw_sftp::Extended::new(session);
+    let local_root = trim_root(&sync.local).to_string();
+    let remote_root = remote_path(&sftp.canonicalize(".").await?, &sync.remote);
+    // a missing destination is created, a missing source is an error
+    let local_exists = tokio::fs::try_exists(&local_root).await?;
+    let remote_exists = sftp.try_exists(remote_root.as_str()).await?;
+    if !sync.reverse && !remote_exists{
+        println!("mkdir {}",remote_root);
+        if !sync.dry_run{
+            sftp.create_dir(remote_root.as_str()).await?;
+        }
+    }
+    if sync.reverse && !local_exists{
+        println!("mkdir {}",local_root);
+        if !sync.dry_run{
+            tokio::fs::create_dir_all(&local_root).await?;
+        }
+    }
+    let local = if local_exists{ local_tree(&local_root, &filter).await? }else{ BTreeMap::new() };
+    let remote = if remote_exists{ remote_tree(&sftp, &remote_root, &filter).await? }else{ BTreeMap::new() };
+    let (source, dest) = if sync.reverse{ (&remote, &local) }else{ (&local, &remote) };
+    info!("sync {} source entries, {} destination entries",source.len(),dest.len());
+
+    let mut transferred = 0;
+    // directories are made when a file is copied into them, so include filters leave no empty ones behind.
+    // without include filters every directory of the source is made, empty ones too
+    let mut made = BTreeSet::new();
+    // parents sort before their children
+    for (rel, entry) in source{
+        let local_path = join(&local_root, rel);
+        let remote_path = join(&remote_root, rel);
+        let target = dest.get(rel);
+        if let Some(target) = target{
+            if target.is_dir != entry.is_dir{
+                return Err(format!("{}: file and directory do not match",rel).into());
+            }
+        }
+        if entry.is_dir{
+            if filter.include.is_empty() && !dest.contains_key(rel) && made.insert(rel.clone()){
+                make_dir(&sync, &sftp, &local_root, &remote_root, rel).await?;
+            }
+            continue;
+        }
+        if !filter.included(rel){
+            continue;
+        }
+        if let Some(target) = target{
+            let changed = if target.size != entry.size{
+                true
+            }else if sync.checksum{
+                checksum::local_sha256(&local_path).await? != checksum::remote_sha256(&ext, &sftp, &remote_path, &options).await?
+            }else{
+                target.mtime != entry.mtime
+            };
+            if !changed{
+                continue;
+            }
+        }
+        for parent in parents(rel){
+            if !dest.contains_key(parent) && made.insert(parent.to_string()){
+                make_dir(&sync, &sftp, &local_root, &remote_root, parent).await?;
+            }
+        }
+        transferred += 1;
+        if sync.reverse{
+            println!("download {}",rel);
+            if !sync.dry_run{
+                sftp::download(&ext, &sftp, &remote_path, &local_path, &options).await?;
+                // keep the mtime, or the next sync sees the file as changed
+                let file = std::fs::File::options().write(true).open(&local_path)?;
+                file.set_modified(from_unix(entry.mtime))?;
+            }
+        }else{
+            println!("upload {}",rel);
+            if !sync.dry_run{
+                sftp::upload(&ext, &sftp, &local_path, &remote_path, &options).await?;
+                // sftp v3 times are unsigned 32 bit, such a file is sent again by every sync
+                match u32::try_from(entry.mtime){
+                    Ok(mtime) =>{
+                        let attrs = FileAttributes{ atime: Some(mtime), mtime: Some(mtime), ..FileAttributes::empty() };
+                        sftp.set_metadata(remote_path.as_str(), attrs).await?;
+                    }
+                    Err(_) => eprintln!("{}: mtime {} does not fit the 32 bit sftp time, not kept",rel,entry.mtime),
+                }
+            }
+        }
+    }
+
+    let mut deleted = 0;
+    if sync.delete{
+        // children sort after their parents, so walk backwards
+        for (rel, entry) in dest.iter().rev(){
+            if source.contains_key(rel) || (!entry.is_dir && !filter.included(rel)){
+                continue;
+            }
+            println!("delete {}",rel);
+            deleted += 1;
+            if sync.dry_run{
+                continue;
+            }
+            let res: std::result::Result<(),Box<dyn std::error::Error>> = match (sync.reverse, entry.is_dir){
+                (true, true) => tokio::fs::remove_dir(join(&local_root, rel)).await.map_err(|e| e.into()),
+                (true, false) => tokio::fs::remove_file(join(&local_root, rel)).await.map_err(|e| e.into()),
+                (false, true) => sftp.remove_dir(join(&remote_root, rel)).await.map_err(|e| e.into()),
+                (false, false) => sftp.remove_file(join(&remote_root, rel)).await.map_err(|e| e.into()),
+            };
+            // a directory still holding excluded files stays
+            match res{
+                Err(e) if entry.is_dir => eprintln!("can't delete {}: {}",rel,e),
+                res => res?,
+            }
+        }
+    }
+    println!("{} transferred, {} deleted{}",transferred,deleted,if sync.dry_run{ " (dry run)" }else{ "" });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn filter(include: &[&str],exclude: &[&str]) -> Filter{
+        let parse = |globs: &[&str]| globs.iter().map(|g| Pattern::new(g).unwrap()).collect();
+        Filter{ include: parse(include), exclude: parse(exclude) }
+    }
+
+    #[test]
+    fn globs_without_slash_match_the_name(){
+        let filter = filter(&["*.rs"], &["target"]);
+        assert!(filter.included("main.rs"));
+        assert!(filter.included("src/deep/lib.rs"));
+        assert!(!filter.included("Cargo.toml"));
+        assert!(filter.excluded("target"));
+        assert!(filter.excluded("sub/target"));
+        assert!(!filter.excluded("targets"));
+    }
+
+    #[test]
+    fn globs_with_slash_match_the_path(){
+        let filter = filter(&["src/*.rs"], &["build/*"]);
+        assert!(filter.included("src/main.rs"));
+        assert!(!filter.included("main.rs"));
+        assert!(filter.excluded("build/out"));
+        assert!(!filter.excluded("out"));
+    }
+
+    #[test]
+    fn no_include_includes_everything(){
+        let filter = filter(&[], &[]);
+        assert!(filter.included("anything/at/all"));
+        assert!(!filter.excluded("anything"));
+    }
+
+    #[test]
+    fn parents_outermost_first(){
+        assert_eq!(parents("a/b/c.txt").collect::<Vec<_>>(), vec!["a", "a/b"]);
+        assert_eq!(parents("c.txt").count(), 0);
+    }
+
+    #[test]
+    fn trim_roots(){
+        assert_eq!(trim_root("/"), "/");
+        assert_eq!(trim_root("//"), "/");
+        assert_eq!(trim_root("/data/"), "/data");
+        assert_eq!(trim_root("data//"), "data");
+        assert_eq!(join(trim_root("/"), "etc/hosts"), "/etc/hosts");
+    }
+
+    #[test]
+    fn join_paths(){
+        assert_eq!(join("/root", ""), "/root");
+        assert_eq!(join("", "a"), "a");
+        assert_eq!(join("/root", "a/b"), "/root/a/b");
+    }
+
+    #[test]
+    fn unix_time_before_the_epoch(){
+        assert_eq!(to_unix(from_unix(-86400)), -86400);
+        assert_eq!(to_unix(from_unix(1700000000)), 1700000000);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use futures::future::try_join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use russh_sftp::client::{Config, SftpSession, fs::File};
+use russh_sftp::protocol::OpenFlags;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use log::info;
+
+pub const CHUNK_SIZE: usize = 32768;
+pub const CONCURRENCY: usize = 16;
+
+/// how files are copied over sftp
+#[derive(clap::Args, Debug, Clone)]
+pub struct TransferOptions{
+    /// bytes per sftp read/write request
+    #[arg(long, default_value_t = CHUNK_SIZE)]
+    pub chunk_size: usize,
+    /// number of sftp file handles, each with one read/write request in flight
+    #[arg(long, default_value_t = CONCURRENCY)]
+    pub concurrency: usize,
+    /// compare the sha256 of both sides after the transfer
+    #[arg(long, default_value_t = false)]
+    pub verify: bool,
+    /// upload to a temporary file and rename it over the remote file, needs posix-rename@openssh.com
+    #[arg(long, default_value_t = false)]
+    pub atomic: bool,
+    /// --limit-rate of the connection underneath, replies wait behind the bytes in flight
+    #[arg(skip)]
+    pub limit_rate: Option<u64>,
+}
+
+impl TransferOptions{
+    /// bytes in flight for one file, a chunk per handle
+    pub fn window(&self) -> std::result::Result<usize,Box<dyn std::error::Error>>{
+        self.chunk_size.max(1).checked_mul(self.concurrency.max(1)).ok_or_else(|| "--chunk-size times --concurrency is too large".into())
+    }
+
+    /// handles worth opening for a file of `len` bytes, one per chunk up to `concurrency`
+    fn handles(&self,len: Option<u64>) -> usize{
+        let concurrency = self.concurrency.max(1);
+        match len{
+            Some(len) => len.div_ceil(self.chunk_size.max(1) as u64).clamp(1, concurrency as u64) as usize,
+            None => concurrency,
+        }
+    }
+}
+
+/// the sftp session the transfers are tuned for, the handles already keep
+/// requests in flight so each one waits for its write to be acknowledged.
+/// under a rate limit a request may wait for a whole window to pass before its reply
+pub fn session_config(options: &TransferOptions) -> std::result::Result<Config,Box<dyn std::error::Error>>{
+    let mut config = Config{ max_concurrent_writes: 1, ..Config::default() };
+    if let Some(rate) = options.limit_rate{
+        config.request_timeout_secs += (options.window()? as u64).div_ceil(rate.max(1));
+    }
+    Ok(config)
+}
+
+/// read a whole chunk at `offset`, a short chunk means the end of the file
+async fn read_chunk(mut file: File,offset: u64,chunk_size: usize) -> (File,u64,std::io::Result<Vec<u8>>){
+    let mut data = vec![0; chunk_size];
+    let mut len = 0;
+    let res = async{
+        file.seek(SeekFrom::Start(offset)).await?;
+        while len < chunk_size{
+            let n = file.read(&mut data[len..]).await?;
+            if n == 0{
+                break;
+            }
+            len += n;
+        }
+        data.truncate(len);
+        Ok(data)
+    }.await;
+    (file,offset,res)
+}
+
+async fn write_chunk(mut file: File,offset: u64,data: Vec<u8>) -> (File,std::io::Result<()>){
+    let res = async{
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(&data).await
+    }.await;
+    (file,res)
+}
+
+/// copy a remote file into `writer`, keeping `concurrency` reads in flight.
+/// chunks are written in order, so `writer` does not need to be seekable.
+pub async fn download<W>(sftp: &SftpSession,remote: &str,writer: &mut W,options: &TransferOptions) -> std::result::Result<u64,Box<dyn std::error::Error>>
+where
+    W: AsyncWrite + Unpin{
+    let chunk_size = options.chunk_size.max(1);
+    let window = options.window()? as u64;
+    let first = sftp.open(remote).await?;
+    let len = first.metadata().await?.size;
+    let mut idle = vec![first];
+    idle.extend(try_join_all((1..options.handles(len)).map(|_| sftp.open(remote))).await?);
+    let mut pending = FuturesUnordered::new();
+    let mut chunks = BTreeMap::new();
+    // offset of the next request, and of the next byte handed to the writer
+    let mut next_offset = 0u64;
+    let mut written = 0u64;
+    let mut eof = false;
+    loop{
+        // do not run further ahead of the writer than one round of requests
+        while !eof && next_offset < written + window{
+            let Some(file) = idle.pop() else { break };
+            pending.push(read_chunk(file, next_offset, chunk_size));
+            next_offset += chunk_size as u64;
+        }
+        let Some((file,offset,res)) = pending.next().await else { break };
+        idle.push(file);
+        let data = res?;
+        if data.len() < chunk_size{
+            eof = true;
+        }
+        chunks.insert(offset, data);
+        while let Some(data) = chunks.remove(&written){
+            if data.is_empty(){
+                break;
+            }
+            writer.write_all(&data).await?;
+            written += data.len() as u64;
+            if data.len() < chunk_size{
+                break;
+            }
+        }
+    }
+    writer.flush().await?;
+    try_join_all(idle.iter_mut().map(|file| file.shutdown())).await?;
+    info!("download {} finish:{} bytes",remote,written);
+    Ok(written)
+}
+
+/// copy `reader` into a new remote file, keeping `concurrency` writes in flight,
+/// or one per chunk when `len` says the file is smaller. a handle returns before its write is acknowledged, a failed write surfaces
+/// on the next write through that handle or at the final shutdown.
+pub async fn upload<R>(sftp: &SftpSession,reader: &mut R,remote: &str,len: Option<u64>,options: &TransferOptions) -> std::result::Result<u64,Box<dyn std::error::Error>>
+where
+    R: AsyncRead + Unpin{
+    let chunk_size = options.chunk_size.max(1);
+    // the first handle truncates the file, the others only write into it
+    let mut idle = vec![sftp.create(remote).await?];
+    idle.extend(try_join_all((1..options.handles(len)).map(|_| sftp.open_with_flags(remote, OpenFlags::WRITE))).await?);
+    let mut pending = FuturesUnordered::new();
+    let mut offset = 0u64;
+    let mut eof = false;
+    while !eof || !pending.is_empty(){
+        while !eof && !idle.is_empty(){
+            let mut data = vec![0; chunk_size];
+            let mut len = 0;
+            while len < chunk_size{
+                let n = reader.read(&mut data[len..]).await?;
+                if n == 0{
+                    eof = true;
+                    break;
+                }
+                len += n;
+            }
+            if len == 0{
+                break;
+            }
+            data.truncate(len);
+            pending.push(write_chunk(idle.pop().unwrap(), offset, data));
+            offset += len as u64;
+        }
+        if let Some((file,res)) = pending.next().await{
+            res?;
+            idle.push(file);
+        }
+    }
+    try_join_all(idle.iter_mut().map(|file| file.shutdown())).await?;
+    info!("upload {} finish:{} bytes",remote,offset);
+    Ok(offset)
+}
+
+// no helpers or imports here, benches/sftp_transfer.rs builds this file without the tests
+#[cfg(test)]
+mod tests{
+    #[test]
+    fn handles_one_per_chunk(){
+        let options = super::TransferOptions{ chunk_size: 32768, concurrency: 16, verify: false, atomic: false, limit_rate: None };
+        assert_eq!(options.handles(Some(0)), 1);
+        assert_eq!(options.handles(Some(100)), 1);
+        assert_eq!(options.handles(Some(32768)), 1);
+        assert_eq!(options.handles(Some(32769)), 2);
+        assert_eq!(options.handles(Some(u64::MAX)), 16);
+        assert_eq!(options.handles(None), 16);
+    }
+
+    #[test]
+    fn window_overflow(){
+        let options = super::TransferOptions{ chunk_size: 32768, concurrency: 16, verify: false, atomic: false, limit_rate: None };
+        assert_eq!(options.window().unwrap(), 32768 * 16);
+        assert_eq!(super::TransferOptions{ chunk_size: 0, concurrency: 0, ..options.clone() }.window().unwrap(), 1);
+        assert!(super::TransferOptions{ chunk_size: usize::MAX, concurrency: 2, ..options }.window().is_err());
+    }
+
+    #[test]
+    fn request_timeout_covers_the_window(){
+        let options = super::TransferOptions{ chunk_size: 32768, concurrency: 16, verify: false, atomic: false, limit_rate: None };
+        assert_eq!(super::session_config(&options).unwrap().request_timeout_secs, 10);
+        let limited = super::TransferOptions{ limit_rate: Some(1024), ..options.clone() };
+        assert_eq!(super::session_config(&limited).unwrap().request_timeout_secs, 10 + 512);
+        let limited = super::TransferOptions{ limit_rate: Some(300), concurrency: 1, ..options };
+        assert_eq!(super::session_config(&limited).unwrap().request_timeout_secs, 10 + 110);
+    }
+}